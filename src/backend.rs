@@ -0,0 +1,104 @@
+//! The installers that actually put toolchains into the pool.
+//!
+//! Rynzland only orchestrates transactions over links and pool entries; the
+//! heavy lifting of downloading and unpacking toolchains is delegated to a
+//! [`Backend`], which is [`RustupBackend`] by default.
+
+use std::{fmt, path::Path};
+
+use anyhow::Result;
+
+pub use crate::rustup::RustupBackend;
+use crate::{Ctx, toolchain::ToolchainDesc};
+
+#[cfg(test)]
+mod fake;
+
+#[cfg(test)]
+pub use self::fake::FakeBackend;
+
+/// The operations on toolchains that rynzland delegates to an installer.
+///
+/// All paths passed in are absolute and located in `ctx.rustup_home`.
+///
+/// ```no_run
+/// use std::path::Path;
+///
+/// use anyhow::{Result, bail};
+/// use rynzland::{Channel, Ctx, Pool, ToolchainDesc, backend::Backend};
+///
+/// /// Only installs stable toolchains, from a local mirror.
+/// #[derive(Debug)]
+/// struct StableOnly;
+///
+/// impl Backend for StableOnly {
+///     fn setup(&self, _ctx: &Ctx) -> Result<()> {
+///         Ok(())
+///     }
+///
+///     fn fetch_manifest(
+///         &self,
+///         _ctx: &Ctx,
+///         toolchain: &ToolchainDesc,
+///         _dest: &Path,
+///     ) -> Result<()> {
+///         if toolchain.channel != Channel::Stable {
+///             bail!("`{toolchain}` is not mirrored");
+///         }
+///         todo!()
+///     }
+///
+///     fn install(
+///         &self,
+///         _ctx: &Ctx,
+///         _toolchain: &ToolchainDesc,
+///         _components: &[String],
+///         _dest: &Path,
+///     ) -> Result<()> {
+///         todo!()
+///     }
+///
+///     fn modify_components(
+///         &self,
+///         _ctx: &Ctx,
+///         _dir: &Path,
+///         _components: &[String],
+///         _add: bool,
+///     ) -> Result<()> {
+///         todo!()
+///     }
+/// }
+///
+/// let pool = Pool::new(Ctx::new("home").with_backend(StableOnly));
+/// pool.install("stable", None, &[], false)?;
+/// # anyhow::Ok(())
+/// ```
+pub trait Backend: fmt::Debug + Send + Sync {
+    /// Prepares the installer for both `ctx.rustup_home` and
+    /// `ctx.rynzland_home`, whose `toolchains` directories already exist.
+    fn setup(&self, ctx: &Ctx) -> Result<()>;
+
+    /// Fetches the channel manifest of `toolchain` into `dest`, failing with
+    /// [`Error::ManifestNotFound`](crate::Error::ManifestNotFound) if it
+    /// doesn't exist.
+    fn fetch_manifest(&self, ctx: &Ctx, toolchain: &ToolchainDesc, dest: &Path) -> Result<()>;
+
+    /// Installs `toolchain` with the extra `components` on top of the
+    /// minimal profile, moving the result to `dest`.
+    fn install(
+        &self,
+        ctx: &Ctx,
+        toolchain: &ToolchainDesc,
+        components: &[String],
+        dest: &Path,
+    ) -> Result<()>;
+
+    /// Adds or removes the `components` of the toolchain at `dir` in place.
+    fn modify_components(
+        &self,
+        ctx: &Ctx,
+        dir: &Path,
+        components: &[String],
+        add: bool,
+    ) -> Result<()>;
+}
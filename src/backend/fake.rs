@@ -0,0 +1,261 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    env::consts::EXE_SUFFIX,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    Ctx, Error,
+    backend::Backend,
+    toolchain::{self, CHANNEL_MANIFEST_SUBPATH, COMPONENTS_SUBPATH, Channel, ToolchainDesc},
+    util::{self, BUILD_TARGET, Rollback, qualify_with_target},
+};
+
+/// A [`Backend`] fabricating toolchains locally, for testing the transaction
+/// logic without network access.
+///
+/// A fabricated toolchain only consists of its channel manifest, its
+/// `components` file, and a dummy file under `bin` for each component.
+///
+/// Channels, including dated ones like `nightly-2025-06-01`, resolve to the
+/// versions registered via [`FakeBackend::set_channel`], while version numbers
+/// like `1.80` or `1.80.0` resolve to themselves. Clones share the same
+/// registry.
+#[derive(Debug, Clone, Default)]
+pub struct FakeBackend {
+    channels: Arc<Mutex<HashMap<String, String>>>,
+
+    /// The components made unavailable, keyed by channel.
+    unavailable: Arc<Mutex<HashMap<String, BTreeSet<String>>>>,
+
+    /// The channels whose manifests can't be fetched.
+    unreachable: Arc<Mutex<BTreeSet<String>>>,
+    latency: Duration,
+}
+
+/// The components listed in every manifest.
+const COMPONENTS: &[&str] = &[
+    "rustc",
+    "cargo",
+    "rust-std",
+    "rust-mingw",
+    "clippy",
+    "rustfmt",
+    "rust-src",
+    "rust-docs",
+    "rust-analyzer",
+    "miri",
+];
+
+/// The `renames` table of every manifest.
+const RENAMES: &[(&str, &str)] = &[("clippy-preview", "clippy"), ("rustfmt-preview", "rustfmt")];
+
+/// The value of `date` in the manifests of undated channels.
+const DATE: &str = "2024-09-05";
+
+impl FakeBackend {
+    #[must_use]
+    pub fn new() -> Self {
+        let this = Self::default();
+        this.set_channel("stable", "1.81.0");
+        this.set_channel("beta", "1.82.0-beta.1");
+        this.set_channel("nightly", "1.83.0-nightly");
+        this
+    }
+
+    /// Makes every installation and component modification take at least
+    /// `latency`, so that concurrent transactions are likely to overlap.
+    #[must_use]
+    pub const fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Makes `channel` resolve to the Rust version `version`.
+    pub fn set_channel(&self, channel: &str, version: &str) {
+        self.channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(channel.to_owned(), version.to_owned());
+    }
+
+    /// Makes `component` unavailable in the manifest of `channel`.
+    pub fn set_unavailable(&self, channel: &str, component: &str) {
+        self.unavailable
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(channel.to_owned())
+            .or_default()
+            .insert(component.to_owned());
+    }
+
+    /// Makes fetching the manifest of `channel` fail, like a server error
+    /// would.
+    pub fn set_unreachable(&self, channel: &str) {
+        self.unreachable
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(channel.to_owned());
+    }
+
+    /// Returns the value of `pkg.rust.version` for `desc`.
+    fn rust_ver(&self, desc: &ToolchainDesc) -> Result<String> {
+        let channel = desc.manifest_name();
+        let registered = self
+            .channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&channel)
+            .cloned();
+        let ver = match (registered, &desc.channel) {
+            (Some(ver), _) => ver,
+            (None, Channel::Version(ver)) if desc.date.is_none() => {
+                match ver.matches('.').count() {
+                    1 => format!("{ver}.0"),
+                    _ => ver.clone(),
+                }
+            }
+            _ => return Err(Error::ManifestNotFound(desc.to_string()).into()),
+        };
+        Ok(format!("{ver} (fake)"))
+    }
+
+    fn manifest(&self, desc: &ToolchainDesc) -> Result<String> {
+        let rust_ver = self.rust_ver(desc)?;
+        let date = desc.date.as_deref().unwrap_or(DATE);
+        let mut manifest = format!(
+            "manifest-version = \"2\"\ndate = \"{date}\"\n\n[pkg.rust]\nversion = \"{rust_ver}\"\n"
+        );
+        let unavailable = self
+            .unavailable
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&desc.manifest_name())
+            .cloned()
+            .unwrap_or_default();
+        for comp in COMPONENTS {
+            let available = !unavailable.contains(*comp);
+            write!(
+                manifest,
+                "\n[pkg.{comp}.target.{BUILD_TARGET}]\navailable = {available}\n"
+            )?;
+        }
+        for (from, to) in RENAMES {
+            write!(manifest, "\n[renames.{from}]\nto = \"{to}\"\n")?;
+        }
+        Ok(manifest)
+    }
+}
+
+/// Returns the path of the dummy file standing for the (qualified) `comp`.
+fn comp_path(dir: &Path, comp: &str) -> PathBuf {
+    let comp = comp
+        .strip_suffix(&format!("-{BUILD_TARGET}"))
+        .unwrap_or(comp);
+    dir.join("bin").join(format!("{comp}{EXE_SUFFIX}"))
+}
+
+fn write_components(dir: &Path, comps: &BTreeSet<String>) -> Result<()> {
+    let mut contents = String::new();
+    for comp in comps {
+        contents.push_str(comp);
+        contents.push('\n');
+    }
+    fs::write(dir.join(*COMPONENTS_SUBPATH), contents)?;
+    Ok(())
+}
+
+impl Backend for FakeBackend {
+    fn setup(&self, _ctx: &Ctx) -> Result<()> {
+        Ok(())
+    }
+
+    fn fetch_manifest(&self, _ctx: &Ctx, toolchain: &ToolchainDesc, dest: &Path) -> Result<()> {
+        let channel = toolchain.manifest_name();
+        if self
+            .unreachable
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(&channel)
+        {
+            anyhow::bail!("failed to fetch the manifest of `{channel}`: 500 Internal Server Error");
+        }
+        fs::write(dest, self.manifest(toolchain)?)?;
+        Ok(())
+    }
+
+    fn install(
+        &self,
+        _ctx: &Ctx,
+        toolchain: &ToolchainDesc,
+        components: &[String],
+        dest: &Path,
+    ) -> Result<()> {
+        let manifest = self.manifest(toolchain)?;
+        thread::sleep(self.latency);
+
+        let tmp = util::with_tmp(dest);
+        fs::create_dir(&tmp)?;
+        let mut rollback = Rollback::new();
+        rollback.push(&tmp);
+
+        let manifest_path = tmp.join(*CHANNEL_MANIFEST_SUBPATH);
+        fs::create_dir_all(manifest_path.parent().unwrap())?;
+        fs::write(manifest_path, manifest)?;
+
+        let comps = toolchain::default_components()
+            .chain(
+                components
+                    .iter()
+                    .map(|c| qualify_with_target(c).into_owned()),
+            )
+            .collect::<BTreeSet<_>>();
+        fs::create_dir(tmp.join("bin"))?;
+        for comp in &comps {
+            fs::write(comp_path(&tmp, comp), comp)?;
+        }
+        write_components(&tmp, &comps)?;
+
+        fs::rename(&tmp, dest)?;
+        rollback.commit();
+        Ok(())
+    }
+
+    fn modify_components(
+        &self,
+        _ctx: &Ctx,
+        dir: &Path,
+        components: &[String],
+        add: bool,
+    ) -> Result<()> {
+        thread::sleep(self.latency);
+
+        let installed = fs::read_to_string(dir.join(*COMPONENTS_SUBPATH))?;
+        let mut installed = installed
+            .lines()
+            .map(ToOwned::to_owned)
+            .collect::<BTreeSet<_>>();
+        for comp in components {
+            let comp = qualify_with_target(comp).into_owned();
+            let path = comp_path(dir, &comp);
+            if add {
+                fs::write(path, &comp)?;
+                installed.insert(comp);
+            } else {
+                installed
+                    .remove(&comp)
+                    .then_some(())
+                    .with_context(|| format!("toolchain does not contain component `{comp}`"))?;
+                fs::remove_file(path)?;
+            }
+        }
+        write_components(dir, &installed)
+    }
+}
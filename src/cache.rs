@@ -0,0 +1,154 @@
+//! The content-addressed cache of the packages downloaded for pool entries.
+//!
+//! The packages are named after their SHA-256 hashes listed in the channel
+//! manifests, just like in rustup's own download directory, so that they can
+//! be hard-linked into the latter and reused by rustup as is.
+
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result, bail};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{
+    Ctx,
+    entry::ENTRY_METADATA,
+    toolchain::{CHANNEL_MANIFEST_SUBPATH, COMPONENTS_SUBPATH, Manifest},
+    util,
+};
+
+/// How long an unreferenced package is kept after its download, so that it
+/// survives until the installation needing it has created its pool entry.
+const GRACE_PERIOD: Duration = Duration::from_hours(24);
+
+impl Ctx {
+    /// Returns the directory of the download cache.
+    pub(crate) fn download_cache(&self) -> PathBuf {
+        self.rynzland_home.join("cache")
+    }
+}
+
+/// Returns the path of the cached package with the given SHA-256 `hash`,
+/// downloading it from `url` first if it's missing.
+pub fn fetch(ctx: &Ctx, url: &str, hash: &str) -> Result<PathBuf> {
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("invalid package hash `{hash}`");
+    }
+    let cache = ctx.download_cache();
+    let path = cache.join(hash);
+    if path.try_exists()? {
+        info!("reusing cached package {hash}...");
+        return Ok(path);
+    }
+
+    fs::create_dir_all(&cache)?;
+    let partial = tempfile::Builder::new()
+        .prefix(hash)
+        .suffix(".partial")
+        .tempfile_in(&cache)?;
+    util::download_file(url, partial.path())?;
+    let found = sha256(partial.path())?;
+    if found != hash {
+        bail!("hash mismatch for `{url}`: expected `{hash}`, found `{found}`");
+    }
+    // NOTE: A concurrent download of the same package can only have produced the
+    // same contents, so it's fine to replace it.
+    partial.persist(&path)?;
+    Ok(path)
+}
+
+fn sha256(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 64 << 10];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().iter().fold(String::new(), |mut acc, b| {
+        _ = write!(acc, "{b:02x}");
+        acc
+    }))
+}
+
+/// Returns the hashes of the packages that the pool `entry` has been installed
+/// from.
+fn referenced_packages(entry: &Path) -> Result<Vec<String>> {
+    let manifest = Manifest::load(&entry.join(*CHANNEL_MANIFEST_SUBPATH))?;
+    let components = fs::read_to_string(entry.join(*COMPONENTS_SUBPATH))
+        .with_context(|| format!("when reading components of {}", entry.display()))?;
+    Ok(components
+        .lines()
+        .flat_map(|comp| manifest.pkg_bins(comp))
+        .map(|(_, hash)| hash.to_owned())
+        .collect())
+}
+
+impl Ctx {
+    /// Removes the cached packages that are not part of any pool entry and
+    /// have been downloaded before the [`GRACE_PERIOD`], including the
+    /// abandoned partial downloads.
+    ///
+    /// Must be called with the pool GC lock held.
+    pub(crate) fn gc_download_cache(&self) -> Result<()> {
+        let cache = self.download_cache();
+        if !cache.try_exists()? {
+            return Ok(());
+        }
+
+        let mut referenced = HashSet::new();
+        for entry in self.rustup_home.join("toolchains").read_dir()? {
+            let entry = entry?.path();
+            // NOTE: Temporary entries might be incomplete, but they can only be
+            // referencing the packages of their base entries.
+            if util::is_tmp(&entry) || !entry.join(ENTRY_METADATA).exists() {
+                continue;
+            }
+            match referenced_packages(&entry) {
+                Ok(it) => referenced.extend(it),
+                Err(e) => {
+                    // NOTE: The packages of a broken entry are unknown, so none of them
+                    // can be told apart from the unreferenced ones. Leave the cache
+                    // alone rather than failing the GC that `doctor` relies on.
+                    warn!(
+                        "skipping download cache GC, failed to read pool entry {}: {e:#}",
+                        entry.display(),
+                    );
+                    return Ok(());
+                }
+            }
+        }
+
+        let now = SystemTime::now();
+        let (mut count, mut size) = (0, 0);
+        for entry in cache.read_dir()? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let meta = entry.metadata()?;
+            let age = now.duration_since(meta.modified()?).unwrap_or_default();
+            if referenced.contains(&*name.to_string_lossy()) || age < GRACE_PERIOD {
+                continue;
+            }
+            util::remove_any(&entry.path())?;
+            count += 1;
+            size += meta.len();
+        }
+        if count > 0 {
+            info!(
+                "removed {count} cached package(s), freeing {}",
+                util::human_size(size),
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+//! The user configuration of rynzland.
+
+use std::{collections::BTreeSet, fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// The name of the configuration file in `ctx.rynzland_home`.
+pub const CONFIG: &str = "rynzland.toml";
+
+/// The contents of [`CONFIG`], where every item is optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /// Whether the IDs of new pool entries also cover the manifest dates and
+    /// the package hashes, i.e. follow the second ID scheme, so that rebuilds
+    /// of the same version get separate entries.
+    ///
+    /// By default, toolchains of the same version and components share their
+    /// entries, e.g. dated nightlies whose versions collide.
+    pub strict_ids: bool,
+
+    pub gc: GcConfig,
+}
+
+/// The retention policies of the pool GC, which keep some of the pool entries
+/// no longer referenced by any toolchain link from being removed.
+///
+/// By default, nothing is retained.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct GcConfig {
+    /// The IDs of the pool entries that are never removed.
+    pub pins: BTreeSet<String>,
+
+    /// How long a pool entry is kept after it has been found unreferenced, in
+    /// seconds.
+    pub grace_period: u64,
+
+    /// How many of the most recently installed versions of each channel are
+    /// kept.
+    pub keep_last: usize,
+
+    /// The maximum size of the pool in bytes, beyond which the least recently
+    /// used pool entries are evicted after each installation regardless of the
+    /// policies above, except for the pins.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_pool_size: Option<u64>,
+
+    /// Whether the pool entries still referenced by toolchain links may be
+    /// evicted as well, once the unreferenced ones are gone, in which case the
+    /// links are removed along with them.
+    pub evict_referenced: bool,
+}
+
+impl Config {
+    /// Returns the version of the ID scheme new pool entries are named after.
+    #[must_use]
+    pub const fn id_scheme(&self) -> u32 {
+        if self.strict_ids { 2 } else { 1 }
+    }
+
+    /// Reads the configuration in `rynzland_home`, returning the default one
+    /// if there is none.
+    pub fn load(rynzland_home: &Path) -> Result<Self> {
+        let path = rynzland_home.join(CONFIG);
+        match fs::read_to_string(&path) {
+            Ok(it) => toml::from_str(&it)
+                .with_context(|| format!("when reading config at {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the configuration into `rynzland_home`, replacing the existing
+    /// one atomically.
+    pub fn write(&self, rynzland_home: &Path) -> Result<()> {
+        let tmp = tempfile::NamedTempFile::new_in(rynzland_home)?;
+        fs::write(tmp.path(), toml::to_string(self)?)?;
+        tmp.persist(rynzland_home.join(CONFIG))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! The metadata recorded in each pool entry at install time.
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::toolchain::{IdentifiableToolchain, ToolchainName};
+
+/// The name of the metadata file at the root of each pool entry.
+pub const ENTRY_METADATA: &str = "rynzland-entry.toml";
+
+/// The name of the file at the root of each pool entry whose modification time
+/// tells when the entry has last been used, if ever.
+pub const LAST_USED: &str = "rynzland-last-used";
+
+/// The contents of [`ENTRY_METADATA`].
+///
+/// Pool entries without it have either been installed by an older rynzland or
+/// been left behind by a crash before it was written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryMetadata {
+    /// The version of the ID scheme the entry is named after.
+    pub id_scheme: u32,
+
+    /// The (qualified) toolchain the entry has been installed from, if known.
+    pub source: Option<String>,
+
+    /// The value of `pkg.rust.version` in the channel manifest.
+    pub rust_ver: String,
+
+    /// The value of `date` in the channel manifest, if any.
+    pub date: Option<String>,
+
+    /// The (qualified) installed components.
+    pub components: BTreeSet<String>,
+
+    /// When the entry has been installed, in seconds since the Unix epoch.
+    pub installed_at: u64,
+
+    /// The version of rynzland that has installed the entry.
+    pub rynzland_version: String,
+
+    /// When the GC has found the entry unreferenced while retaining it for a
+    /// grace period, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub released_at: Option<u64>,
+}
+
+impl EntryMetadata {
+    /// Describes the entry of `toolchain` installed just now from `source`.
+    #[must_use]
+    pub fn new(toolchain: &IdentifiableToolchain, source: Option<String>) -> Self {
+        Self {
+            id_scheme: toolchain.scheme,
+            source,
+            rust_ver: toolchain.rust_ver.clone(),
+            date: toolchain.date.clone(),
+            components: toolchain.components.clone(),
+            installed_at: unix_now(),
+            rynzland_version: env!("CARGO_PKG_VERSION").to_owned(),
+            released_at: None,
+        }
+    }
+
+    /// Returns the channel of the entry's source, such as `stable` or `1.80`,
+    /// if known.
+    #[must_use]
+    pub fn channel(&self) -> Option<String> {
+        let source = self.source.as_ref()?.parse::<ToolchainName>().ok()?;
+        Some(source.desc().ok()?.channel.to_string())
+    }
+
+    /// Reads the metadata of the pool entry at `entry`, returning `None` if it
+    /// has none.
+    pub fn load(entry: &Path) -> Result<Option<Self>> {
+        let path = entry.join(ENTRY_METADATA);
+        match fs::read_to_string(&path) {
+            Ok(it) => Ok(Some(toml::from_str(&it).with_context(|| {
+                format!("when reading entry metadata at {}", path.display())
+            })?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the metadata into the pool entry at `entry`.
+    pub fn write(&self, entry: &Path) -> Result<()> {
+        fs::write(entry.join(ENTRY_METADATA), toml::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// Records that the pool entry at `entry` is being used right now.
+pub fn touch(entry: &Path) -> Result<()> {
+    fs::File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(entry.join(LAST_USED))?
+        .set_modified(SystemTime::now())?;
+    Ok(())
+}
+
+/// Returns when the pool entry at `entry` with `metadata` has last been used
+/// or installed, in seconds since the Unix epoch.
+pub fn last_used(entry: &Path, metadata: &EntryMetadata) -> u64 {
+    entry
+        .join(LAST_USED)
+        .metadata()
+        .and_then(|it| it.modified())
+        .ok()
+        .and_then(|it| it.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |it| it.as_secs())
+        .max(metadata.installed_at)
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |it| it.as_secs())
+}
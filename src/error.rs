@@ -0,0 +1,105 @@
+use std::error::Error as StdError;
+
+/// The failures of this crate that callers might want to tell apart.
+///
+/// Functions in this crate return [`anyhow::Result`], whose error chain will
+/// contain one of these whenever applicable. Use [`Error::find`] to retrieve
+/// it.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The toolchain link is being modified by another transaction, i.e. its
+    /// in-flight link already exists.
+    #[error("toolchain `{0}` is already being modified by another process")]
+    ToolchainBusy(String),
+
+    /// The pool entry is being installed or repaired by another transaction on
+    /// behalf of a different toolchain link.
+    #[error("pool entry `{0}` is already being installed by another process")]
+    EntryBusy(String),
+
+    /// The toolchain name is malformed or unsafe for use as a file name.
+    #[error("invalid toolchain name `{name}`: {reason}")]
+    InvalidToolchainName { name: String, reason: String },
+
+    /// Some components are not available in the channel manifest of the
+    /// toolchain.
+    #[error(
+        "toolchain `{toolchain}` is missing the component(s) {}",
+        .components.iter().map(|it| format!("`{it}`")).collect::<Vec<_>>().join(", "),
+    )]
+    ComponentsUnavailable {
+        toolchain: String,
+        components: Vec<String>,
+    },
+
+    /// An existing pool entry does not contain the toolchain its ID stands
+    /// for.
+    #[error("pool entry `{id}` contains toolchain `{found}` instead, please run `doctor`")]
+    EntryMismatch { id: String, found: String },
+
+    /// The toolchain link does not exist.
+    #[error("toolchain `{0}` is not installed")]
+    LinkNotFound(String),
+
+    /// The pool GC lock could not be acquired in time.
+    #[error("the pool is being garbage-collected by another process")]
+    GcLockBusy(#[source] gix_lock::acquire::Error),
+
+    /// No channel manifest has been published for the toolchain, e.g. because
+    /// its date has not been archived.
+    #[error("no channel manifest has been published for toolchain `{0}`")]
+    ManifestNotFound(String),
+
+    /// A file could not be downloaded.
+    #[error("failed to download `{url}`")]
+    DownloadFailed {
+        url: String,
+        #[source]
+        source: Box<dyn StdError + Send + Sync>,
+    },
+
+    /// A rustup child process exited unsuccessfully.
+    #[error("command failed: {cmd}\n\n{output}")]
+    RustupFailed { cmd: String, output: String },
+
+    /// The process has received a termination signal.
+    #[error("interrupted by signal")]
+    Interrupted,
+}
+
+impl Error {
+    /// Finds the outermost [`Error`] in the chain of `err`.
+    #[must_use]
+    pub fn find(err: &anyhow::Error) -> Option<&Self> {
+        // NOTE: `anyhow::Error::downcast_ref()` also sees through contexts, which
+        // the chained sources don't.
+        err.downcast_ref()
+            .or_else(|| err.chain().find_map(|it| it.downcast_ref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn find() {
+        let err = anyhow::Error::new(Error::Interrupted).context("while running");
+        assert!(matches!(Error::find(&err), Some(Error::Interrupted)));
+
+        let err = anyhow::Error::from(io::Error::other("oops"))
+            .context(Error::LinkNotFound("stable".into()))
+            .context("while removing");
+        assert!(matches!(Error::find(&err), Some(Error::LinkNotFound(_))));
+
+        let err = Err::<(), _>(io::Error::other("oops"))
+            .context("plain")
+            .unwrap_err();
+        assert!(Error::find(&err).is_none());
+    }
+}
@@ -0,0 +1,26 @@
+//! Named points in the transactions where a crash can be injected, so that
+//! tests can prove the transaction protocol recoverable from each of them.
+
+/// The environment variable naming the point at which the process should
+/// crash. Only honored in tests.
+#[cfg(test)]
+pub const FAULT_POINT_ENV: &str = "RYNZLAND_FAULT_POINT";
+
+/// The exit code of a process crashed by [`inject`].
+#[cfg(test)]
+pub const FAULT_EXIT_CODE: i32 = 86;
+
+/// Exits the process on the spot if `point` is named by [`FAULT_POINT_ENV`],
+/// simulating a crash that skips all destructors, including those of
+/// [`Rollback`](crate::util::Rollback)s and locks.
+#[cfg(test)]
+pub fn inject(point: &str) {
+    if std::env::var_os(FAULT_POINT_ENV).is_some_and(|it| it == point) {
+        std::process::exit(FAULT_EXIT_CODE);
+    }
+}
+
+/// Does nothing outside of tests.
+#[cfg(not(test))]
+#[inline]
+pub const fn inject(_point: &str) {}
@@ -1,16 +1,19 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     ffi::{OsStr, OsString},
-    process::Command,
+    fs,
+    path::Path,
 };
 
 use anyhow::Result;
-use gix_lock::Marker;
+use gix_lock::{Marker, acquire};
 use tracing::info;
 
 use crate::{
-    Ctx,
-    util::{self, CommandExt},
+    Ctx, EntryMetadata, Error,
+    config::{Config, GcConfig},
+    entry::{self, ENTRY_METADATA},
+    fault, util,
 };
 
 impl Ctx {
@@ -18,7 +21,16 @@ impl Ctx {
     /// `self.rustup_home` that are no longer referenced by any of the toolchain
     /// links. If `candidates` is `None`, then it defaults to all underlying
     /// toolchains.
-    pub fn gc<S, I>(&self, candidates: impl Into<Option<I>>) -> Result<()>
+    ///
+    /// The toolchains kept by the retention policies in [`GcConfig`] are not
+    /// removed either.
+    ///
+    /// The toolchains are moved to the trash before being deleted, so that a
+    /// half-deleted one is never visible under its ID. Whatever a crash has
+    /// left in the trash is deleted by the next run.
+    ///
+    /// Returns the IDs of the removed toolchains.
+    pub fn gc<S, I>(&self, candidates: impl Into<Option<I>>) -> Result<Vec<String>>
     where
         S: AsRef<OsStr>,
         I: IntoIterator<Item = S>,
@@ -27,16 +39,21 @@ impl Ctx {
             .into()
             .map(|cs| cs.into_iter().map(|it| it.as_ref().to_owned()).collect());
         if candidates.as_ref().is_some_and(HashSet::is_empty) {
-            return Ok(());
+            return Ok(vec![]);
         }
 
         // Now entering the critical section.
         let pool = self.rustup_home.join("toolchains");
-        let _lock = Marker::acquire_to_hold_resource(
-            pool.join("pool_gc.lock"),
-            self.gc_lock_backoff,
-            None,
-        )?;
+        let _lock = self.lock_gc()?;
+
+        let trash = self.rustup_home.join("trash");
+        if trash.try_exists()? {
+            for entry in trash.read_dir()? {
+                let path = entry?.path();
+                info!("removing trashed toolchain {}...", path.display());
+                util::remove_any(&path)?;
+            }
+        }
 
         let mut referenced = HashSet::new();
         let walker = self.rynzland_home.join("toolchains").read_dir()?;
@@ -47,28 +64,172 @@ impl Ctx {
                 referenced.insert(name.to_owned());
             }
         }
+        let retained = self.retained(&pool, &referenced)?;
 
-        let rm = |tc: &OsString| {
+        let mut removed = vec![];
+        let mut rm = |tc: &OsString| {
             info!(
                 "underlying toolchain {} is no longer referenced, removing...",
                 tc.display(),
             );
-            self.set_env_local(&mut Command::new(&self.rustup))
-                .arg("uninstall")
-                .arg(tc)
-                .run_checked()
+            self.remove_entry(tc)?;
+            removed.push(tc.to_string_lossy().into_owned());
+            anyhow::Ok(())
         };
 
         let Some(candidates) = &candidates else {
-            for tc in referenced {
+            for entry in pool.read_dir()? {
+                let entry = entry?;
+                let tc = entry.file_name();
+                // NOTE: Pool entries are always directories, which excludes the lock
+                // file and the links of rustup's own. Directories without metadata
+                // might not be ours, so they are left for `doctor` to look into.
+                if !entry.file_type()?.is_dir()
+                    || util::is_tmp(&tc)
+                    || referenced.contains(&tc)
+                    || retained.contains(&tc)
+                    || !entry.path().join(ENTRY_METADATA).exists()
+                {
+                    continue;
+                }
                 rm(&tc)?;
             }
-            return Ok(());
+            self.gc_download_cache()?;
+            return Ok(removed);
         };
 
         for tc in candidates.difference(&referenced) {
-            rm(tc)?;
+            if retained.contains(tc) {
+                info!(
+                    "underlying toolchain {} is retained, skipping...",
+                    tc.display()
+                );
+                continue;
+            }
+            // NOTE: A concurrent GC might have removed the candidate in the meantime.
+            if pool.join(tc).try_exists()? {
+                rm(tc)?;
+            }
         }
+        Ok(removed)
+    }
+
+    /// Acquires the pool GC lock, failing with [`Error::GcLockBusy`] if it
+    /// can't be acquired in time.
+    pub(crate) fn lock_gc(&self) -> Result<Marker> {
+        let path = self.rustup_home.join("toolchains").join("pool_gc.lock");
+        Marker::acquire_to_hold_resource(path, self.gc_lock_backoff, None).map_err(|e| match e {
+            acquire::Error::PermanentlyLocked { .. } => Error::GcLockBusy(e).into(),
+            acquire::Error::Io(e) => anyhow::Error::from(e),
+        })
+    }
+
+    /// Removes the pool entry `id` by moving it to the trash before deleting
+    /// it, so that a half-deleted entry is never visible under its ID.
+    ///
+    /// Must be called with the pool GC lock held.
+    pub(crate) fn remove_entry(&self, id: &OsStr) -> Result<()> {
+        fault::inject("gc:removing");
+        // NOTE: The trash is next to the pool, so the renaming is atomic. Each
+        // entry gets a unique directory there in case the same ID gets trashed
+        // again before the deletion, after a crash.
+        let trash = self.rustup_home.join("trash");
+        fs::create_dir_all(&trash)?;
+        let slot = tempfile::Builder::new()
+            .prefix(id)
+            .tempdir_in(&trash)?
+            .keep();
+        fs::rename(self.rustup_home.join("toolchains").join(id), slot.join(id))?;
+        fault::inject("gc:trashed");
+        fs::remove_dir_all(&slot)?;
         Ok(())
     }
+
+    /// Returns the pool entries to be kept by the retention policies, starting
+    /// the grace periods of the newly unreferenced entries and ending those of
+    /// the referenced ones.
+    fn retained(&self, pool: &Path, referenced: &HashSet<OsString>) -> Result<HashSet<OsString>> {
+        let GcConfig {
+            pins,
+            grace_period,
+            keep_last,
+            ..
+        } = Config::load(&self.rynzland_home)?.gc;
+        let mut retained = pins.into_iter().map(OsString::from).collect::<HashSet<_>>();
+        if grace_period == 0 && keep_last == 0 {
+            return Ok(retained);
+        }
+
+        let mut entries = vec![];
+        for entry in pool.read_dir()? {
+            let entry = entry?;
+            let id = entry.file_name();
+            if !entry.file_type()?.is_dir() || util::is_tmp(&id) {
+                continue;
+            }
+            if let Some(metadata) = EntryMetadata::load(&entry.path())? {
+                entries.push((id, metadata));
+            }
+        }
+
+        // The versions of each channel, along with when they were last installed.
+        let mut versions = HashMap::<String, HashMap<String, u64>>::new();
+        for (_, metadata) in &entries {
+            let Some(channel) = metadata.channel() else {
+                continue;
+            };
+            let installed_at = versions
+                .entry(channel)
+                .or_default()
+                .entry(metadata.rust_ver.clone())
+                .or_default();
+            *installed_at = (*installed_at).max(metadata.installed_at);
+        }
+        let mut kept_versions = HashSet::new();
+        for (channel, versions) in versions {
+            let mut versions = versions
+                .into_iter()
+                .map(|(ver, at)| (at, ver))
+                .collect::<Vec<_>>();
+            // NOTE: Ties are broken by the versions, as the installation times are
+            // only precise to the second.
+            versions.sort_unstable_by(|a, b| b.cmp(a));
+            kept_versions.extend(
+                versions
+                    .into_iter()
+                    .take(keep_last)
+                    .map(|(_, ver)| (channel.clone(), ver)),
+            );
+        }
+
+        let now = entry::unix_now();
+        for (id, mut metadata) in entries {
+            let path = pool.join(&id);
+            if referenced.contains(&id) {
+                if metadata.released_at.take().is_some() {
+                    metadata.write(&path)?;
+                }
+                continue;
+            }
+            let mut keep = metadata
+                .channel()
+                .is_some_and(|it| kept_versions.contains(&(it, metadata.rust_ver.clone())));
+            if grace_period > 0 {
+                // NOTE: The metadata is only written when the grace period starts,
+                // not on every GC.
+                let released_at = if let Some(it) = metadata.released_at {
+                    it
+                } else {
+                    metadata.released_at = Some(now);
+                    metadata.write(&path)?;
+                    now
+                };
+                keep |= now.saturating_sub(released_at) < grace_period;
+            }
+            if keep {
+                retained.insert(id);
+            }
+        }
+        Ok(retained)
+    }
 }
@@ -1,28 +1,49 @@
 use std::{
     borrow::Cow,
-    fs, iter,
+    io, iter,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, ExitStatus},
+    sync::Arc,
 };
 
 use anyhow::Result;
 use argh::FromArgs;
 use gix_lock::acquire::Fail;
-use tracing::info;
+use tracing::warn;
 
 use crate::{
-    toolchain::IdentifiableToolchain,
-    util::{CommandExt, qualify_with_target},
+    backend::{Backend, RustupBackend},
+    util::CommandExt,
 };
 
+pub mod backend;
+mod cache;
+mod config;
+mod entry;
+mod error;
+mod fault;
 mod gc;
+mod pool;
+mod quota;
+mod report;
 mod rustup;
+pub mod signal;
 mod toolchain;
+mod usage;
 mod util;
 
 #[cfg(test)]
 mod test;
 
+pub use crate::{
+    config::{Config, GcConfig},
+    entry::EntryMetadata,
+    error::Error,
+    pool::{LinkedToolchain, Pool, ToolchainUsage},
+    report::{Format, LinkChange, Output, PinChange, Report},
+    toolchain::{Channel, IdentifiableToolchain, ToolchainDesc, ToolchainName},
+};
+
 #[derive(Debug, Clone)]
 pub struct Ctx {
     pub home: PathBuf,
@@ -31,8 +52,13 @@ pub struct Ctx {
     pub rynzland_home: PathBuf,
     pub cargo_home: PathBuf,
     gc_lock_backoff: Fail,
+    backend: Arc<dyn Backend>,
+    dist_server: String,
 }
 
+/// The root URL of the official Rust distribution server.
+pub const DEFAULT_DIST_SERVER: &str = "https://static.rust-lang.org";
+
 impl Ctx {
     #[must_use]
     pub fn new(home: impl AsRef<Path>) -> Self {
@@ -44,9 +70,29 @@ impl Ctx {
             cargo_home: home.join("cargo_home"),
             home,
             gc_lock_backoff: Fail::Immediately,
+            backend: Arc::new(RustupBackend),
+            dist_server: DEFAULT_DIST_SERVER.to_owned(),
         }
     }
 
+    /// Fetches rustup, the channel manifests and the toolchains from the
+    /// mirror at `url` instead of [`DEFAULT_DIST_SERVER`], as rustup would
+    /// with `RUSTUP_DIST_SERVER`.
+    #[must_use]
+    pub fn with_dist_server(mut self, url: impl Into<String>) -> Self {
+        let mut url = url.into();
+        url.truncate(url.trim_end_matches('/').len());
+        self.dist_server = url;
+        self
+    }
+
+    /// Replaces the [`RustupBackend`] with another installer.
+    #[must_use]
+    pub fn with_backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
     #[must_use]
     pub fn with_gc_lock_backoff(mut self, backoff: impl Into<Option<Fail>>) -> Self {
         self.gc_lock_backoff = backoff.into().unwrap_or_default();
@@ -56,17 +102,58 @@ impl Ctx {
     pub fn set_env_local<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
         cmd.env("RUSTUP_HOME", &self.rustup_home)
             .env("CARGO_HOME", &self.cargo_home)
+            .env("RUSTUP_DIST_SERVER", &self.dist_server)
+    }
+
+    /// Returns the directory holding the private `RUSTUP_HOME`s of the ongoing
+    /// transactions, which is next to the pool so that entries can be renamed
+    /// across.
+    fn staging_dir(&self) -> PathBuf {
+        self.rustup_home.join("staging")
     }
 
     pub fn set_env_rynzland<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
         cmd.env("RUSTUP_HOME", &self.rynzland_home)
             .env("CARGO_HOME", &self.cargo_home)
+            .env("RUSTUP_DIST_SERVER", &self.dist_server)
+    }
+
+    /// Returns the path of the link to the (qualified) `toolchain`, failing
+    /// with [`Error::LinkNotFound`] if it doesn't exist.
+    fn existing_link(&self, toolchain: &str) -> Result<PathBuf> {
+        let link = self.rynzland_home.join("toolchains").join(toolchain);
+        match link.symlink_metadata() {
+            Ok(_) => Ok(link),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(Error::LinkNotFound(toolchain.to_owned()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
     }
 }
 
 /// Hey choom, mind giving me a hand?
 #[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(
+    error_code(2, "Any other failure."),
+    error_code(
+        3,
+        "The toolchain or its pool entry is being modified by another process."
+    ),
+    error_code(4, "The toolchain is not installed."),
+    error_code(5, "The pool is being garbage-collected by another process."),
+    error_code(6, "A download has failed, or the channel manifest is missing."),
+    error_code(7, "A rustup child process has failed."),
+    error_code(8, "The toolchain name is invalid."),
+    error_code(9, "A component is not available for the toolchain."),
+    error_code(10, "A pool entry does not contain the toolchain of its ID."),
+    error_code(130, "The process has been interrupted by a termination signal.")
+)]
 pub struct Rynzland {
+    /// the output format of the results, either `text` (default) or `json`
+    #[argh(option, default = "Format::Text")]
+    pub format: Format,
+
     #[argh(subcommand)]
     pub subcmd: RynzlandSubcmd,
 }
@@ -79,25 +166,37 @@ pub enum RynzlandSubcmd {
     Rm(RmSubCmd),
     Run(RunSubCmd),
     Nuke(NukeSubcmd),
+    List(ListSubcmd),
+    Gc(GcSubcmd),
+    Stats(StatsSubcmd),
+    Doctor(DoctorSubcmd),
     Id(IdSubcmd),
     IdChan(IdChanSubcmd),
     CompAdd(CompAddSubcmd),
     CompRm(CompRmSubcmd),
+    Pin(PinSubcmd),
+    Unpin(UnpinSubcmd),
 }
 
 impl RynzlandSubcmd {
-    pub fn run(self, ctx: &Ctx) -> Result<()> {
-        match self {
-            Self::Setup(cmd) => cmd.run(ctx),
-            Self::Add(cmd) => cmd.run(ctx),
-            Self::Rm(cmd) => cmd.run(ctx),
-            Self::Run(cmd) => cmd.run(ctx),
-            Self::Nuke(cmd) => cmd.run(ctx),
-            Self::Id(cmd) => cmd.run(ctx),
-            Self::IdChan(cmd) => cmd.run(ctx),
-            Self::CompAdd(cmd) => cmd.run(ctx),
-            Self::CompRm(cmd) => cmd.run(ctx),
-        }
+    pub fn run(self, ctx: &Ctx) -> Result<Output> {
+        Ok(match self {
+            Self::Setup(cmd) => cmd.run(ctx)?.into(),
+            Self::Add(cmd) => cmd.run(ctx)?.into(),
+            Self::Rm(cmd) => cmd.run(ctx)?.into(),
+            Self::Run(cmd) => cmd.run(ctx)?.into(),
+            Self::Nuke(cmd) => cmd.run(ctx)?.into(),
+            Self::List(cmd) => cmd.run(ctx)?.into(),
+            Self::Gc(cmd) => cmd.run(ctx)?.into(),
+            Self::Stats(cmd) => cmd.run(ctx)?.into(),
+            Self::Doctor(cmd) => cmd.run(ctx)?.into(),
+            Self::Id(cmd) => cmd.run(ctx)?.into(),
+            Self::IdChan(cmd) => cmd.run(ctx)?.into(),
+            Self::CompAdd(cmd) => cmd.run(ctx)?.into(),
+            Self::CompRm(cmd) => cmd.run(ctx)?.into(),
+            Self::Pin(cmd) => cmd.run(ctx)?.into(),
+            Self::Unpin(cmd) => cmd.run(ctx)?.into(),
+        })
     }
 }
 
@@ -127,6 +226,24 @@ pub struct CompRmSubcmd {
     components: Vec<String>,
 }
 
+/// keep a pool entry from being garbage-collected
+#[derive(FromArgs, Clone, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "pin")]
+pub struct PinSubcmd {
+    /// the ID of the pool entry to pin
+    #[argh(positional)]
+    id: String,
+}
+
+/// allow a pinned pool entry to be garbage-collected again
+#[derive(FromArgs, Clone, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "unpin")]
+pub struct UnpinSubcmd {
+    /// the ID of the pool entry to unpin
+    #[argh(positional)]
+    id: String,
+}
+
 /// set up a local rustup installation
 #[derive(FromArgs, Clone, Copy, PartialEq, Eq, Debug)]
 #[argh(subcommand, name = "setup")]
@@ -141,6 +258,15 @@ pub struct AddSubcmd {
     #[argh(option, short = 's')]
     source: Option<String>,
 
+    /// extra components to install on top of the minimal profile
+    #[argh(option, short = 'c')]
+    components: Vec<String>,
+
+    /// fall back to the newest nightly having all the components if the
+    /// latest one is missing some of them
+    #[argh(switch)]
+    allow_downgrade: bool,
+
     /// the toolchain to install
     #[argh(positional)]
     toolchain: String,
@@ -177,6 +303,30 @@ pub struct RunSubCmd {
 #[argh(subcommand, name = "nuke")]
 pub struct NukeSubcmd {}
 
+/// list the installed toolchains along with their IDs
+#[derive(FromArgs, Clone, Copy, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "list")]
+pub struct ListSubcmd {}
+
+/// remove all unreferenced toolchains from the pool
+#[derive(FromArgs, Clone, Copy, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "gc")]
+pub struct GcSubcmd {
+    /// also remove the toolchain links that haven't been run for this many days
+    #[argh(option)]
+    pub unused_days: Option<u64>,
+}
+
+/// show how often and how recently each toolchain has been run
+#[derive(FromArgs, Clone, Copy, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "stats")]
+pub struct StatsSubcmd {}
+
+/// recover from interrupted transactions, assuming no others are running
+#[derive(FromArgs, Clone, Copy, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "doctor")]
+pub struct DoctorSubcmd {}
+
 /// print the ID of a toolchain
 #[derive(FromArgs, Clone, PartialEq, Eq, Debug)]
 #[argh(subcommand, name = "id")]
@@ -200,122 +350,32 @@ pub struct IdChanSubcmd {
 
 impl SetupSubcmd {
     #[allow(clippy::unused_self)]
-    pub fn run(self, ctx: &Ctx) -> Result<()> {
-        if ctx.rustup.try_exists()? {
-            info!("rustup already set up, skipping...");
-        } else {
-            info!("setting up rustup...");
-            rustup::setup(&ctx.rustup)?;
-        }
-        info!("setting up FS link to local rustup...");
-        let local_cargo_bin = ctx.cargo_home.join("bin");
-
-        for dir in [
-            &local_cargo_bin,
-            &ctx.rustup_home.join("toolchains"),
-            &ctx.rynzland_home.join("toolchains"),
-        ] {
-            fs::create_dir_all(dir)?;
-        }
-
-        let local_rustup_link = local_cargo_bin.join("rustup");
-        if !local_rustup_link.try_exists()? {
-            #[cfg(unix)]
-            util::soft_link(&ctx.rustup, &local_rustup_link)?;
-
-            #[cfg(windows)]
-            fs::hard_link(&*ctx.rustup, &local_rustup_link)?;
-        }
-
-        for home in [&ctx.rustup_home, &ctx.rynzland_home] {
-            Command::new(&ctx.rustup)
-                .env("RUSTUP_HOME", home)
-                .args(["set", "profile", "minimal"])
-                .run_checked()?;
-
-            Command::new(&ctx.rustup)
-                .env("RUSTUP_HOME", home)
-                .args(["set", "auto-install", "disable"])
-                .run_checked()?;
-
-            Command::new(&ctx.rustup)
-                .env("RUSTUP_HOME", home)
-                .args(["set", "auto-self-update", "disable"])
-                .run_checked()?;
-        }
-        Ok(())
+    pub fn run(self, ctx: &Ctx) -> Result<Report> {
+        Pool::new(ctx.clone()).setup()
     }
 }
 
 impl AddSubcmd {
-    pub fn run(&self, ctx: &Ctx) -> Result<()> {
-        let toolchain = qualify_with_target(&self.toolchain);
-        let src = self
-            .source
-            .as_deref()
-            .map_or_else(|| Cow::Borrowed(&toolchain), qualify_with_target);
-
-        let chan = src
-            .strip_suffix(&format!("-{}", util::BUILD_TARGET))
-            .unwrap();
-        let id = toolchain::resolve_channel(chan, &[])?.id();
-
-        if toolchain == src {
-            info!("adding toolchain: {toolchain} (id: {id})");
-        } else {
-            info!("adding toolchain: {toolchain} from source {src} (id: {id})");
-        }
-
-        // TODO: Use juntion on Windows
-        let src_old = ctx.rustup_home.join("toolchains").join(&*src);
-        let src_with_id = ctx.rustup_home.join("toolchains").join(&id);
-        let link = ctx.rynzland_home.join("toolchains").join(&*toolchain);
-
-        // NOTE: We create the in-flight link first to declare the beginning of the
-        // transaction of the `link` toolchain creation.
-        let link_in_flight = util::with_tmp(&link);
-        util::soft_link(&src_with_id, &link_in_flight)?;
-
-        // Save the original underlying toolchain for GC later.
-        let underlying = util::soft_link_target(&link).ok();
-        let underlying = underlying.as_ref().map(|it| it.file_name().unwrap());
-
-        if src_with_id.exists() {
-            info!("toolchain with id {id} already installed, skipping...");
-        } else {
-            ctx.set_env_local(&mut Command::new(&ctx.rustup))
-                .args(["install", &src])
-                .run_checked()?;
-            fs::rename(&src_old, &src_with_id)?;
-        }
-
-        // NOTE: Renaming is atomic on most platforms.
-        // This also declares the successful end of the transaction.
-        fs::rename(&link_in_flight, &link)?;
-
-        if let Some(underlying) = underlying {
-            ctx.gc([underlying])?;
-        }
-        Ok(())
+    pub fn run(&self, ctx: &Ctx) -> Result<Report> {
+        Pool::new(ctx.clone()).install(
+            &self.toolchain,
+            self.source.as_deref(),
+            &self.components,
+            self.allow_downgrade,
+        )
     }
 }
 
 impl RmSubCmd {
-    pub fn run(&self, ctx: &Ctx) -> Result<()> {
-        let toolchain = qualify_with_target(&self.toolchain);
-        info!("removing toolchain: {toolchain}");
-
-        let link = ctx.rynzland_home.join("toolchains").join(&*toolchain);
-        let link_target = util::soft_link_target(&link)?;
-        let underlying = link_target.file_name().unwrap();
-
-        util::soft_unlink(&link)?;
-        ctx.gc([underlying])
+    pub fn run(&self, ctx: &Ctx) -> Result<Report> {
+        Pool::new(ctx.clone()).remove(&self.toolchain)
     }
 }
 
 impl RunSubCmd {
-    pub fn run(&self, ctx: &Ctx) -> Result<()> {
+    /// Runs the shim, returning its exit status for the caller to pass on
+    /// rather than failing if it's unsuccessful.
+    pub fn run(&self, ctx: &Ctx) -> Result<ExitStatus> {
         let Self {
             shim,
             args,
@@ -323,145 +383,110 @@ impl RunSubCmd {
         } = self;
         let mut args = Cow::Borrowed(args);
         if let Some(toolchain) = toolchain {
+            toolchain.parse::<ToolchainName>()?;
             args = Cow::Owned(
                 iter::once(format!("+{toolchain}"))
                     .chain(args.iter().cloned())
                     .collect(),
             );
         }
+        // NOTE: Without an explicit toolchain, it's up to rustup to pick one, so
+        // its use can't be recorded.
+        if let Some(used) = args.first().and_then(|it| it.strip_prefix('+'))
+            && let Err(e) = Pool::new(ctx.clone()).record_use(used)
+        {
+            warn!("failed to record the use of toolchain `{used}`: {e:#}");
+        }
         ctx.set_env_rynzland(&mut Command::new(&ctx.rustup))
             .env("RUSTUP_FORCE_ARG0", shim)
             .args(&*args)
-            .run_checked()
+            .run_inherited()
     }
 }
 
 impl NukeSubcmd {
     #[allow(clippy::unused_self)]
-    pub fn run(self, ctx: &Ctx) -> Result<()> {
-        info!("nuking local rustup installation...");
-
-        let walker = ctx.home.read_dir()?;
-        for entry in walker {
-            let entry = entry?;
-            let file_type = entry.file_type()?;
-            if file_type.is_symlink() || file_type.is_file() {
-                if entry.file_name() == ".gitkeep" {
-                    continue;
-                }
-                fs::remove_file(entry.path())?;
-            } else if file_type.is_dir() {
-                fs::remove_dir_all(entry.path())?;
-            }
-        }
+    pub fn run(self, ctx: &Ctx) -> Result<Report> {
+        Pool::new(ctx.clone()).nuke()
+    }
+}
 
-        Ok(())
+impl ListSubcmd {
+    #[allow(clippy::unused_self)]
+    pub fn run(self, ctx: &Ctx) -> Result<Vec<LinkedToolchain>> {
+        Pool::new(ctx.clone()).list()
     }
 }
 
-impl IdSubcmd {
-    pub fn run(&self, ctx: &Ctx) -> Result<()> {
-        let toolchain = qualify_with_target(&self.toolchain);
-        let toolchain_path = ctx.rynzland_home.join("toolchains").join(&*toolchain);
-        let id = IdentifiableToolchain::new(&toolchain_path)?.id();
-        println!("{id}");
-        Ok(())
+impl GcSubcmd {
+    pub fn run(self, ctx: &Ctx) -> Result<Report> {
+        let pool = Pool::new(ctx.clone());
+        let mut report = match self.unused_days {
+            Some(days) => pool.remove_unused(days)?,
+            None => Report::default(),
+        };
+        report.removed.extend(pool.gc()?);
+        Ok(report)
     }
 }
 
-impl IdChanSubcmd {
+impl StatsSubcmd {
     #[allow(clippy::unused_self)]
-    pub fn run(&self, _ctx: &Ctx) -> Result<()> {
-        let id_toolchain = toolchain::resolve_channel(&self.channel, &self.components)?;
-        println!("{}", id_toolchain.id());
-        Ok(())
+    pub fn run(self, ctx: &Ctx) -> Result<Vec<ToolchainUsage>> {
+        Pool::new(ctx.clone()).stats()
     }
 }
 
-impl CompAddSubcmd {
-    pub fn run(&self, ctx: &Ctx) -> Result<()> {
-        ctx.modify_components(&self.toolchain, &self.components, true)
+impl DoctorSubcmd {
+    #[allow(clippy::unused_self)]
+    pub fn run(self, ctx: &Ctx) -> Result<Report> {
+        Ok(Report {
+            removed: Pool::new(ctx.clone()).doctor()?,
+            ..Report::default()
+        })
     }
 }
 
-impl CompRmSubcmd {
-    pub fn run(&self, ctx: &Ctx) -> Result<()> {
-        ctx.modify_components(&self.toolchain, &self.components, false)
+impl IdSubcmd {
+    pub fn run(&self, ctx: &Ctx) -> Result<IdentifiableToolchain> {
+        Pool::new(ctx.clone()).identify(&self.toolchain)
     }
 }
 
-impl Ctx {
-    fn modify_components(&self, toolchain: &str, comps: &[String], add: bool) -> Result<()> {
-        if comps.is_empty() {
-            info!("no components specified, skipping...");
-            return Ok(());
-        }
-
-        let toolchain = qualify_with_target(toolchain);
-        let link = self.rynzland_home.join("toolchains").join(&*toolchain);
-
-        let underlying_path = link.canonicalize()?;
-        let mut underlying = IdentifiableToolchain::new(&underlying_path)?;
-
-        for comp in comps {
-            let comp = util::qualify_with_target(comp);
-            if add {
-                underlying.components.insert(comp.into_owned());
-            } else {
-                underlying.components.remove(&*comp);
-            }
-        }
-
-        let old_id = underlying_path.file_name().unwrap();
-        let new_id = underlying.id();
-
-        let new_toolchain_dir = self.rustup_home.join("toolchains").join(&new_id);
-
-        // NOTE: We create the in-flight link first to declare the beginning of the
-        // transaction of the `link` toolchain creation.
-        let link_in_flight = util::with_tmp(&link);
-        util::soft_link(&new_toolchain_dir, &link_in_flight)?;
+impl IdChanSubcmd {
+    pub fn run(&self, ctx: &Ctx) -> Result<IdentifiableToolchain> {
+        Pool::new(ctx.clone()).identify_channel(&self.channel, &self.components)
+    }
+}
 
-        if new_toolchain_dir.exists() {
-            info!("toolchain with id {new_id} already exists, switching...");
-        } else {
-            info!("creating toolchain {new_id}...");
-            let tmp_dir = util::with_tmp(&new_toolchain_dir);
+impl CompAddSubcmd {
+    pub fn run(&self, ctx: &Ctx) -> Result<Report> {
+        Pool::new(ctx.clone()).add_components(&self.toolchain, &self.components)
+    }
+}
 
-            info!(
-                "cloning {} into {}...",
-                underlying_path.display(),
-                tmp_dir.display()
-            );
+impl CompRmSubcmd {
+    pub fn run(&self, ctx: &Ctx) -> Result<Report> {
+        Pool::new(ctx.clone()).remove_components(&self.toolchain, &self.components)
+    }
+}
 
-            // NOTE: This will likely error out if the underlying toolchain exists, because
-            // the first `fs::create_dir()` will fail in the first place.
-            util::copy_dir_all(&underlying_path, &tmp_dir)?;
-
-            let op = if add { "add" } else { "remove" };
-
-            // HACK: We will have to make rustup think that `toolchain_name` is an official
-            // toolchain, so it has to use an official name. This logic shouldn't exist in
-            // the final version. Anyway, following the current naming scheme, a
-            // toolchain in the pool can never have the name `"stable-<host>"`, so it's
-            // fine.
-            let toolchain_name = util::qualify_with_target("stable");
-            let hack_link = tmp_dir.with_file_name(toolchain_name.as_ref());
-            util::soft_link(&tmp_dir, &hack_link)?;
-            self.set_env_local(&mut Command::new(&self.rustup))
-                .env("RUSTUP_TOOLCHAIN", &*toolchain_name)
-                .arg("component")
-                .arg(op)
-                .args(comps)
-                .run_checked()?;
-            util::soft_unlink(&hack_link)?;
-
-            fs::rename(&tmp_dir, &new_toolchain_dir)?;
-        }
+impl PinSubcmd {
+    pub fn run(&self, ctx: &Ctx) -> Result<PinChange> {
+        let changed = Pool::new(ctx.clone()).pin(&self.id, true)?;
+        Ok(PinChange {
+            pinned: changed.then(|| self.id.clone()).into_iter().collect(),
+            ..PinChange::default()
+        })
+    }
+}
 
-        // NOTE: Renaming is atomic on most platforms.
-        // This also declares the successful end of the transaction.
-        fs::rename(&link_in_flight, &link)?;
-        self.gc([old_id])
+impl UnpinSubcmd {
+    pub fn run(&self, ctx: &Ctx) -> Result<PinChange> {
+        let changed = Pool::new(ctx.clone()).pin(&self.id, false)?;
+        Ok(PinChange {
+            unpinned: changed.then(|| self.id.clone()).into_iter().collect(),
+            ..PinChange::default()
+        })
     }
 }
@@ -1,13 +1,94 @@
+//! The `rynzland` binary.
+//!
+//! # Exit codes
+//!
+//! | Code  | Meaning                                                    |
+//! | ----- | ---------------------------------------------------------- |
+//! | `0`   | Success.                                                   |
+//! | `1`   | The command line is invalid.                               |
+//! | `2`   | Any other failure.                                         |
+//! | `3`   | The toolchain or its pool entry is being modified by       |
+//! |       | another process.                                           |
+//! | `4`   | The toolchain is not installed.                            |
+//! | `5`   | The pool is being garbage-collected by another process.    |
+//! | `6`   | A download has failed, or the channel manifest is missing. |
+//! | `7`   | A rustup child process has failed.                         |
+//! | `8`   | The toolchain name is invalid.                             |
+//! | `9`   | A component is not available for the toolchain.            |
+//! | `10`  | A pool entry does not contain the toolchain of its ID.     |
+//! | `130` | The process has been interrupted by a termination signal.  |
+//!
+//! Once `run` has started the shim, the exit code of the latter is passed on
+//! instead, or 128 plus the signal number if it has been killed by a signal.
+
+use std::{
+    env, io,
+    process::{ExitCode, ExitStatus},
+};
+
 use anyhow::Result;
-use rynzland::{Ctx, Rynzland};
+use rynzland::{Ctx, Error, Output, Rynzland, signal};
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+fn main() -> ExitCode {
+    // NOTE: Logs go to stderr so that stdout is reserved for the results.
+    tracing_subscriber::fmt().with_writer(io::stderr).init();
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+fn run() -> Result<ExitCode> {
+    signal::install_handler()?;
     unsafe {
-        std::env::remove_var("RUSTUP_TOOLCHAIN");
+        env::remove_var("RUSTUP_TOOLCHAIN");
     }
 
     let app: Rynzland = argh::from_env();
-    let ctx = Ctx::new("home");
-    app.subcmd.run(&ctx)
+    let mut ctx = Ctx::new("home");
+    if let Ok(url) = env::var("RUSTUP_DIST_SERVER") {
+        ctx = ctx.with_dist_server(url);
+    }
+    let output = app.subcmd.run(&ctx)?;
+    if let Output::Exited(status) = output {
+        return Ok(ExitCode::from(shim_exit_code(status)));
+    }
+    if let Some(output) = output.render(app.format)? {
+        println!("{output}");
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Maps `err` to the process exit code documented above.
+fn exit_code(err: &anyhow::Error) -> u8 {
+    match Error::find(err) {
+        Some(Error::ToolchainBusy(_) | Error::EntryBusy(_)) => 3,
+        Some(Error::LinkNotFound(_)) => 4,
+        Some(Error::GcLockBusy(_)) => 5,
+        Some(Error::DownloadFailed { .. } | Error::ManifestNotFound(_)) => 6,
+        Some(Error::RustupFailed { .. }) => 7,
+        Some(Error::InvalidToolchainName { .. }) => 8,
+        Some(Error::ComponentsUnavailable { .. }) => 9,
+        Some(Error::EntryMismatch { .. }) => 10,
+        Some(Error::Interrupted) => signal::EXIT_INTERRUPTED,
+        _ => 2,
+    }
+}
+
+/// Maps the exit `status` of a shim to the exit code of this process, the way
+/// shells do.
+fn shim_exit_code(status: ExitStatus) -> u8 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return u8::try_from(signal).map_or(2, |it| it.saturating_add(128));
+    }
+    // NOTE: Exit codes beyond `u8` are only possible on Windows, where they can't
+    // be passed on through `ExitCode` anyway.
+    status
+        .code()
+        .and_then(|it| u8::try_from(it).ok())
+        .unwrap_or(2)
 }
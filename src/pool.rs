@@ -0,0 +1,755 @@
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, Result, bail};
+use gix_lock::{
+    Marker,
+    acquire::{self, Fail},
+};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    Config, Ctx, EntryMetadata, Error, LinkChange, Report, entry, fault, signal,
+    toolchain::{self, CHANNEL_MANIFEST_SUBPATH, IdentifiableToolchain, Manifest, ToolchainName},
+    usage::{USAGE_DB, UsageDb},
+    util::{self, Rollback, qualify_with_target},
+};
+
+/// A handle to a toolchain pool and the links referencing it.
+///
+/// This is the entry point for embedding rynzland as a library, with each
+/// method running a complete transaction. The CLI subcommands are thin
+/// wrappers around these methods.
+///
+/// ```no_run
+/// use rynzland::{Ctx, Pool};
+///
+/// let pool = Pool::new(Ctx::new("home"));
+/// pool.setup()?;
+/// pool.install("stable", None, &["clippy".into()], false)?;
+/// for tc in pool.list()? {
+///     println!("{} -> {}", tc.name, tc.id);
+/// }
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Pool {
+    ctx: Ctx,
+}
+
+/// A toolchain link along with the ID of the pool entry it references.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LinkedToolchain {
+    /// The (qualified) toolchain name.
+    pub name: String,
+
+    /// The ID of the referenced pool entry.
+    pub id: String,
+
+    /// The value of `pkg.rust.version` recorded in the entry's metadata, if
+    /// any.
+    pub version: Option<String>,
+}
+
+/// A toolchain link along with its usage statistics.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ToolchainUsage {
+    /// The (qualified) toolchain name.
+    pub name: String,
+
+    /// How many times the toolchain has been run.
+    pub count: u64,
+
+    /// When the toolchain has last been run, in seconds since the Unix epoch,
+    /// if ever.
+    pub last_used: Option<u64>,
+}
+
+impl Pool {
+    #[must_use]
+    pub const fn new(ctx: Ctx) -> Self {
+        Self { ctx }
+    }
+
+    #[must_use]
+    pub const fn ctx(&self) -> &Ctx {
+        &self.ctx
+    }
+
+    /// Sets up a local rustup installation for both the pool and the links.
+    pub fn setup(&self) -> Result<Report> {
+        let ctx = &self.ctx;
+        for dir in [
+            &ctx.rustup_home.join("toolchains"),
+            &ctx.rynzland_home.join("toolchains"),
+        ] {
+            fs::create_dir_all(dir)?;
+        }
+        ctx.backend.setup(ctx)?;
+        Ok(Report::default())
+    }
+
+    /// Installs `toolchain` from `source` (defaulting to `toolchain` itself)
+    /// with the given `components` on top of the minimal profile.
+    ///
+    /// If `allow_downgrade` is set and `source` is a nightly missing some of
+    /// the `components`, the newest archived nightly having all of them is
+    /// installed instead.
+    pub fn install(
+        &self,
+        toolchain: &str,
+        source: Option<&str>,
+        components: &[String],
+        allow_downgrade: bool,
+    ) -> Result<Report> {
+        let ctx = &self.ctx;
+        let toolchain = toolchain.parse::<ToolchainName>()?;
+        let src = source
+            .map(str::parse::<ToolchainName>)
+            .transpose()?
+            .unwrap_or_else(|| toolchain.clone());
+        let src = src.desc()?.check_host()?;
+        let toolchain = toolchain.to_string();
+        let comps = toolchain::default_components()
+            .chain(
+                components
+                    .iter()
+                    .map(|c| qualify_with_target(c).into_owned()),
+            )
+            .collect::<Vec<_>>();
+        let (src, id_toolchain) = if allow_downgrade {
+            toolchain::resolve_newest_available(ctx, src, &comps)?
+        } else {
+            (src.clone(), toolchain::resolve_channel(ctx, src, &comps)?)
+        };
+        let id_toolchain = self.reuse_scheme(id_toolchain)?;
+        let id = id_toolchain.id();
+        // NOTE: These have been normalized through the manifest's renames.
+        let components = id_toolchain
+            .components
+            .difference(&toolchain::default_components().collect())
+            .map(|c| util::unqualify(c).to_owned())
+            .collect::<Vec<_>>();
+
+        if toolchain == src.to_string() {
+            info!("adding toolchain: {toolchain} (id: {id})");
+        } else {
+            info!("adding toolchain: {toolchain} from source {src} (id: {id})");
+        }
+
+        // TODO: Use juntion on Windows
+        let src_with_id = ctx.rustup_home.join("toolchains").join(&id);
+        let link = ctx.rynzland_home.join("toolchains").join(&toolchain);
+
+        // NOTE: We create the in-flight link first to declare the beginning of the
+        // transaction of the `link` toolchain creation.
+        let (link_in_flight, rollback) = begin_link_transaction(&src_with_id, &link)?;
+        fault::inject("add:in-flight");
+
+        // Save the original underlying toolchain for GC later.
+        let underlying = util::soft_link_target(&link).ok();
+        let underlying = underlying.as_ref().map(|it| it.file_name().unwrap());
+
+        let mut report = Report {
+            links: vec![LinkChange {
+                toolchain,
+                from: underlying.map(|it| it.to_string_lossy().into_owned()),
+                to: Some(id.clone()),
+            }],
+            version: Some(id_toolchain.rust_ver.clone()),
+            ..Report::default()
+        };
+
+        // NOTE: Transactions of other links might be installing the same pool entry
+        // concurrently, in which case only one of them may run the installer.
+        let _entry_lock = match check_entry(&src_with_id, &id)? {
+            EntryState::Valid => None,
+            _ => Some(lock_pool_entry(&src_with_id)?),
+        };
+        if check_entry(&src_with_id, &id)?.repair(&src_with_id)? {
+            info!("toolchain with id {id} already installed, skipping...");
+        } else {
+            ctx.backend.install(ctx, &src, &components, &src_with_id)?;
+            EntryMetadata::new(&id_toolchain, Some(src.to_string())).write(&src_with_id)?;
+            report.created.push(id);
+        }
+        fault::inject("add:installed");
+
+        signal::check()?;
+        // NOTE: Renaming is atomic on most platforms.
+        // This also declares the successful end of the transaction.
+        fs::rename(&link_in_flight, &link)?;
+        rollback.commit();
+        fault::inject("add:relinked");
+
+        if let Some(underlying) = underlying {
+            report.removed = ctx.gc([underlying])?;
+        }
+        report.merge(ctx.enforce_quota(src_with_id.file_name().unwrap())?);
+        Ok(report)
+    }
+
+    /// Removes the link to `toolchain`, garbage-collecting the pool entry it
+    /// references if it is no longer in use.
+    pub fn remove(&self, toolchain: &str) -> Result<Report> {
+        let ctx = &self.ctx;
+        let toolchain = toolchain.parse::<ToolchainName>()?.to_string();
+        info!("removing toolchain: {toolchain}");
+
+        let link = ctx.existing_link(&toolchain)?;
+        let link_target = util::soft_link_target(&link)?;
+        let underlying = link_target.file_name().unwrap();
+
+        // NOTE: A concurrent removal might have won the race in the meantime.
+        util::soft_unlink(&link).map_err(|e| match e.downcast_ref::<io::Error>() {
+            Some(io) if io.kind() == io::ErrorKind::NotFound => {
+                e.context(Error::LinkNotFound(toolchain.clone()))
+            }
+            _ => e,
+        })?;
+        if let Err(e) = UsageDb::retain(&ctx.rynzland_home, |it| it != toolchain) {
+            warn!("failed to forget the usage of toolchain `{toolchain}`: {e:#}");
+        }
+        Ok(Report {
+            links: vec![LinkChange {
+                toolchain,
+                from: Some(underlying.to_string_lossy().into_owned()),
+                to: None,
+            }],
+            removed: ctx.gc([underlying])?,
+            ..Report::default()
+        })
+    }
+
+    /// Removes the links of the toolchains that haven't been run for `days`
+    /// days, counting from when they have been linked if that is later.
+    pub fn remove_unused(&self, days: u64) -> Result<Report> {
+        let ctx = &self.ctx;
+        let db = UsageDb::load(&ctx.rynzland_home)?;
+        let deadline = entry::unix_now().saturating_sub(days.saturating_mul(24 * 60 * 60));
+        let mut report = Report::default();
+        for tc in self.list()? {
+            let linked_at = ctx
+                .rynzland_home
+                .join("toolchains")
+                .join(&tc.name)
+                .symlink_metadata()
+                .and_then(|it| it.modified())
+                .ok()
+                .and_then(|it| it.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |it| it.as_secs());
+            let last_used = db.get(&tc.name).map_or(0, |it| it.last_used);
+            if last_used.max(linked_at) >= deadline {
+                continue;
+            }
+            info!("toolchain {} has not been run for {days} days", tc.name);
+            match self.remove(&tc.name) {
+                Ok(it) => report.merge(it),
+                // NOTE: A concurrent removal might have won the race in the meantime.
+                Err(e) if matches!(Error::find(&e), Some(Error::LinkNotFound(_))) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Adds `components` to `toolchain`, relinking it to the resulting pool
+    /// entry.
+    pub fn add_components(&self, toolchain: &str, components: &[String]) -> Result<Report> {
+        self.modify_components(toolchain, components, true)
+    }
+
+    /// Removes `components` from `toolchain`, relinking it to the resulting
+    /// pool entry.
+    pub fn remove_components(&self, toolchain: &str, components: &[String]) -> Result<Report> {
+        self.modify_components(toolchain, components, false)
+    }
+
+    /// Lists all toolchain links, skipping those in flight.
+    pub fn list(&self) -> Result<Vec<LinkedToolchain>> {
+        let pool = self.ctx.rustup_home.join("toolchains");
+        let mut toolchains = vec![];
+        for entry in self.ctx.rynzland_home.join("toolchains").read_dir()? {
+            let entry = entry?;
+            if util::is_tmp(entry.file_name()) {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Ok(target) = util::soft_link_target(entry.path()) else {
+                continue;
+            };
+            let Some(id) = target.file_name() else {
+                continue;
+            };
+            // NOTE: The entry might have been removed or be missing its metadata.
+            let version = EntryMetadata::load(&pool.join(id))
+                .ok()
+                .flatten()
+                .map(|it| it.rust_ver);
+            let id = id.to_string_lossy().into_owned();
+            toolchains.push(LinkedToolchain { name, id, version });
+        }
+        toolchains.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(toolchains)
+    }
+
+    /// Garbage-collects all pool entries that are no longer referenced by any
+    /// toolchain link, returning their IDs.
+    pub fn gc(&self) -> Result<Vec<String>> {
+        let removed = self.ctx.gc::<OsString, Vec<_>>(None)?;
+        // NOTE: Links can also disappear without `remove`, e.g. by quota eviction.
+        let links = self
+            .list()?
+            .into_iter()
+            .map(|tc| tc.name)
+            .collect::<Vec<_>>();
+        if let Err(e) = UsageDb::retain(&self.ctx.rynzland_home, |it| links.iter().any(|l| l == it))
+        {
+            warn!("failed to prune the usage statistics: {e:#}");
+        }
+        Ok(removed)
+    }
+
+    /// Records that `toolchain` is being run right now, both in the usage
+    /// statistics and in the pool entry it references, which the pool size
+    /// quota evicts the pool entries by.
+    pub fn record_use(&self, toolchain: &str) -> Result<()> {
+        let ctx = &self.ctx;
+        let toolchain = toolchain.parse::<ToolchainName>()?.to_string();
+        let link = ctx.existing_link(&toolchain)?;
+        let target = util::soft_link_target(&link)?;
+        let id = target.file_name().context("expected a pool entry")?;
+        entry::touch(&ctx.rustup_home.join("toolchains").join(id))?;
+        UsageDb::record(&ctx.rynzland_home, &toolchain)
+    }
+
+    /// Returns the usage statistics of all toolchain links.
+    pub fn stats(&self) -> Result<Vec<ToolchainUsage>> {
+        let db = UsageDb::load(&self.ctx.rynzland_home)?;
+        Ok(self
+            .list()?
+            .into_iter()
+            .map(|tc| {
+                let usage = db.get(&tc.name);
+                ToolchainUsage {
+                    name: tc.name,
+                    count: usage.map_or(0, |it| it.count),
+                    last_used: usage.map(|it| it.last_used),
+                }
+            })
+            .collect())
+    }
+
+    /// Pins or unpins the pool entry `id` in the configuration, which decides
+    /// whether the GC will always keep it, returning whether that has changed
+    /// anything.
+    ///
+    /// The configuration is updated under the pool GC lock, so that concurrent
+    /// updates are not lost and the entry can't be removed before it's pinned.
+    pub fn pin(&self, id: &str, pinned: bool) -> Result<bool> {
+        let ctx = &self.ctx;
+        let _lock = ctx.lock_gc()?;
+        let mut config = Config::load(&ctx.rynzland_home)?;
+        if pinned {
+            let entry = ctx.rustup_home.join("toolchains").join(id);
+            if Path::new(id).file_name() != Some(id.as_ref()) || !entry.is_dir() {
+                bail!("pool entry `{id}` does not exist");
+            }
+            if !config.gc.pins.insert(id.to_owned()) {
+                info!("pool entry {id} is already pinned, skipping...");
+                return Ok(false);
+            }
+            info!("pinning pool entry {id}...");
+        } else if config.gc.pins.remove(id) {
+            info!("unpinning pool entry {id}...");
+        } else {
+            info!("pool entry {id} is not pinned, skipping...");
+            return Ok(false);
+        }
+        config.write(&ctx.rynzland_home)?;
+        Ok(true)
+    }
+
+    /// Recovers the home from transactions that have crashed midway, returning
+    /// the IDs of the pool entries garbage-collected in the process.
+    ///
+    /// This removes the in-flight links, temporary pool entries and locks left
+    /// behind, and migrates the pool entries named after an outdated ID scheme,
+    /// so it must not run concurrently with any other transaction.
+    pub fn doctor(&self) -> Result<Vec<String>> {
+        let ctx = &self.ctx;
+        for entry in ctx.rynzland_home.join("toolchains").read_dir()? {
+            let path = entry?.path();
+            if util::is_tmp(&path) {
+                info!("removing in-flight link {}...", path.display());
+                util::remove_any(&path)?;
+            }
+        }
+        for entry in ctx.rustup_home.join("toolchains").read_dir()? {
+            let path = entry?.path();
+            if util::is_tmp(&path) {
+                info!("removing temporary pool entry {}...", path.display());
+                util::remove_any(&path)?;
+            } else if path.extension().is_some_and(|ext| ext == "lock") {
+                info!("removing stale lock {}...", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+        let usage_lock = ctx.rynzland_home.join(format!("{USAGE_DB}.lock"));
+        if usage_lock.try_exists()? {
+            info!("removing stale lock {}...", usage_lock.display());
+            fs::remove_file(&usage_lock)?;
+        }
+        let staging = ctx.staging_dir();
+        if staging.try_exists()? {
+            info!("removing private homes in {}...", staging.display());
+            util::remove_any(&staging)?;
+        }
+        self.migrate()?;
+        self.gc()
+    }
+
+    /// Renames every pool entry whose name is not its ID under the configured
+    /// ID scheme, relinking the toolchains referencing it, and writes the
+    /// missing metadata of the entries.
+    ///
+    /// The links are switched before the entry is renamed, so that a crash
+    /// in between can be recovered from by migrating again. If an entry with
+    /// the new ID exists already, the outdated one is left for GC instead.
+    fn migrate(&self) -> Result<()> {
+        let ctx = &self.ctx;
+        let scheme = Config::load(&ctx.rynzland_home)?.id_scheme();
+        let pool = ctx.rustup_home.join("toolchains");
+        for entry in pool.read_dir()? {
+            let entry = entry?;
+            let path = entry.path();
+            if !entry.file_type()?.is_dir() || util::is_tmp(&path) {
+                continue;
+            }
+            let old_id = entry.file_name();
+            let ident = match IdentifiableToolchain::new(&path) {
+                Ok(it) => it.with_scheme(scheme),
+                Err(e) => {
+                    info!(
+                        "skipping unidentifiable pool entry {}: {e:#}",
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+            let id = ident.id();
+            let metadata = EntryMetadata::load(&path)?;
+            if old_id == *id && metadata.is_some() {
+                continue;
+            }
+            // NOTE: The installation time of a legacy entry is unknown, so it's taken
+            // as now.
+            let mut metadata = metadata.unwrap_or_else(|| EntryMetadata::new(&ident, None));
+            metadata.id_scheme = scheme;
+            metadata.write(&path)?;
+            if old_id == *id {
+                continue;
+            }
+            info!(
+                "migrating pool entry {} (ID scheme v{}) to {id}...",
+                old_id.display(),
+                IdentifiableToolchain::id_scheme(&old_id.to_string_lossy()),
+            );
+            let new_path = pool.join(&id);
+            for link in self.list()? {
+                if *link.id == old_id {
+                    let link = ctx.rynzland_home.join("toolchains").join(&link.name);
+                    let (link_in_flight, rollback) = begin_link_transaction(&new_path, &link)?;
+                    fs::rename(&link_in_flight, &link)?;
+                    rollback.commit();
+                }
+            }
+            if !new_path.exists() {
+                fs::rename(&path, &new_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Identifies the pool entry referenced by the `toolchain` link.
+    pub fn identify(&self, toolchain: &str) -> Result<IdentifiableToolchain> {
+        let toolchain = toolchain.parse::<ToolchainName>()?.to_string();
+        // NOTE: The ID scheme is told by the name of the entry, not the link.
+        let toolchain_path = self.ctx.existing_link(&toolchain)?.canonicalize()?;
+        IdentifiableToolchain::new(&toolchain_path)
+    }
+
+    /// Identifies the latest toolchain of `channel` by downloading its
+    /// manifest, with an explicit list of `components` if it is not empty.
+    pub fn identify_channel(
+        &self,
+        channel: &str,
+        components: &[String],
+    ) -> Result<IdentifiableToolchain> {
+        let channel = channel.parse::<ToolchainName>()?;
+        toolchain::resolve_channel(&self.ctx, channel.desc()?.check_host()?, components)
+    }
+
+    /// Removes everything under the home directory.
+    pub fn nuke(&self) -> Result<Report> {
+        info!("nuking local rustup installation...");
+
+        let walker = self.ctx.home.read_dir()?;
+        for entry in walker {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_symlink() || file_type.is_file() {
+                if entry.file_name() == ".gitkeep" {
+                    continue;
+                }
+                fs::remove_file(entry.path())?;
+            } else if file_type.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+
+        Ok(Report::default())
+    }
+
+    /// Returns `toolchain` following the ID scheme of an existing pool entry
+    /// holding it, if the entry named after its own scheme is not valid, so
+    /// that changing the configured scheme doesn't install the same
+    /// toolchain again.
+    fn reuse_scheme(&self, toolchain: IdentifiableToolchain) -> Result<IdentifiableToolchain> {
+        let pool = self.ctx.rustup_home.join("toolchains");
+        let id = toolchain.id();
+        if check_entry(&pool.join(&id), &id)? == EntryState::Valid {
+            return Ok(toolchain);
+        }
+        for scheme in 1..=IdentifiableToolchain::ID_SCHEME {
+            if scheme == toolchain.scheme {
+                continue;
+            }
+            let other = toolchain.clone().with_scheme(scheme);
+            let other_id = other.id();
+            let entry = pool.join(&other_id);
+            // NOTE: An entry named after a looser scheme might hold another build of
+            // the same version, which must not be taken for the requested one.
+            if check_entry(&entry, &other_id)? == EntryState::Valid
+                && IdentifiableToolchain::new(&entry)?
+                    .with_scheme(toolchain.scheme)
+                    .id()
+                    == id
+            {
+                info!("reusing pool entry {other_id} (ID scheme v{scheme}) for {id}...");
+                return Ok(other);
+            }
+        }
+        Ok(toolchain)
+    }
+
+    fn modify_components(&self, toolchain: &str, comps: &[String], add: bool) -> Result<Report> {
+        let ctx = &self.ctx;
+        if comps.is_empty() {
+            info!("no components specified, skipping...");
+            return Ok(Report::default());
+        }
+
+        let toolchain = toolchain.parse::<ToolchainName>()?.to_string();
+        let link = ctx.existing_link(&toolchain)?;
+
+        let underlying_path = link.canonicalize()?;
+        let underlying = IdentifiableToolchain::new(&underlying_path)?;
+
+        // NOTE: Different spellings of the same component must lead to the same ID.
+        let manifest = Manifest::load(&underlying_path.join(*CHANNEL_MANIFEST_SUBPATH))?;
+        let comps = comps
+            .iter()
+            .map(|c| manifest.normalize(&util::qualify_with_target(c)))
+            .collect::<Vec<_>>();
+        if add {
+            // NOTE: Fail fast before cloning the whole toolchain.
+            toolchain::check_available(&manifest, &toolchain, &comps)?;
+        }
+
+        let mut components = underlying.components;
+        for comp in &comps {
+            if add {
+                components.insert(comp.clone());
+            } else {
+                components.remove(comp);
+            }
+        }
+        let scheme = Config::load(&ctx.rynzland_home)?.id_scheme();
+        let underlying = self.reuse_scheme(
+            IdentifiableToolchain::from_manifest(&manifest, components)?.with_scheme(scheme),
+        )?;
+
+        let old_id = underlying_path.file_name().unwrap();
+        let new_id = underlying.id();
+
+        let new_toolchain_dir = ctx.rustup_home.join("toolchains").join(&new_id);
+
+        // NOTE: We create the in-flight link first to declare the beginning of the
+        // transaction of the `link` toolchain creation.
+        let (link_in_flight, mut rollback) = begin_link_transaction(&new_toolchain_dir, &link)?;
+        fault::inject("comp:in-flight");
+
+        let mut report = Report {
+            links: vec![LinkChange {
+                toolchain,
+                from: Some(old_id.to_string_lossy().into_owned()),
+                to: Some(new_id.clone()),
+            }],
+            version: Some(underlying.rust_ver.clone()),
+            ..Report::default()
+        };
+
+        // NOTE: Transactions of other links might be creating the same pool entry
+        // concurrently, in which case only one of them may run the installer.
+        let _entry_lock = match check_entry(&new_toolchain_dir, &new_id)? {
+            EntryState::Valid => None,
+            _ => Some(lock_pool_entry(&new_toolchain_dir)?),
+        };
+        if check_entry(&new_toolchain_dir, &new_id)?.repair(&new_toolchain_dir)? {
+            info!("toolchain with id {new_id} already exists, switching...");
+        } else {
+            info!("creating toolchain {new_id}...");
+            let tmp_dir = util::with_tmp(&new_toolchain_dir);
+
+            info!(
+                "linking {} into {}...",
+                underlying_path.display(),
+                tmp_dir.display()
+            );
+
+            // NOTE: This will likely error out if the underlying toolchain exists, because
+            // the first `fs::create_dir()` will fail in the first place.
+            fs::create_dir(&tmp_dir)?;
+            rollback.push(&tmp_dir);
+            // NOTE: Only the files to be modified in place need to be copied, which
+            // makes this near-instant.
+            util::link_dir_contents(&underlying_path, &tmp_dir, &is_rewritten_in_place)?;
+            fault::inject("comp:cloned");
+            signal::check()?;
+
+            let comps = comps
+                .iter()
+                .map(|c| util::unqualify(c).to_owned())
+                .collect::<Vec<_>>();
+            ctx.backend.modify_components(ctx, &tmp_dir, &comps, add)?;
+            let source = EntryMetadata::load(&underlying_path)?.and_then(|it| it.source);
+            EntryMetadata::new(&underlying, source).write(&tmp_dir)?;
+
+            fs::rename(&tmp_dir, &new_toolchain_dir)?;
+            report.created.push(new_id);
+        }
+        fault::inject("comp:installed");
+
+        signal::check()?;
+        // NOTE: Renaming is atomic on most platforms.
+        // This also declares the successful end of the transaction.
+        fs::rename(&link_in_flight, &link)?;
+        rollback.commit();
+        fault::inject("comp:relinked");
+        report.removed = ctx.gc([old_id])?;
+        report.merge(ctx.enforce_quota(new_toolchain_dir.file_name().unwrap())?);
+        Ok(report)
+    }
+}
+
+/// Returns whether the file at `path` relative to a pool entry might be
+/// rewritten in place when modifying its components, i.e. it is one of our
+/// metadata files or rustup's bookkeeping files under `lib/rustlib`.
+fn is_rewritten_in_place(path: &Path) -> bool {
+    path.parent()
+        .is_none_or(|it| it.as_os_str().is_empty() || it == Path::new("lib/rustlib"))
+}
+
+/// The state of a pool entry expected to contain the toolchain of some ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum EntryState {
+    Missing,
+    Valid,
+
+    /// The entry cannot be identified, e.g. because it is incomplete.
+    Broken,
+
+    /// The entry contains the toolchain of another ID.
+    Mismatch {
+        id: String,
+        found: String,
+    },
+}
+
+impl EntryState {
+    /// Prepares the pool `entry` for reuse, returning whether it can be reused
+    /// as is. A broken entry is removed so that it can be installed again,
+    /// which requires the caller to hold its lock.
+    ///
+    /// Fails with [`Error::EntryMismatch`] if the entry contains the wrong
+    /// toolchain, since others might be relying on it.
+    fn repair(self, entry: &Path) -> Result<bool> {
+        match self {
+            Self::Missing => Ok(false),
+            Self::Valid => Ok(true),
+            Self::Broken => {
+                info!("removing broken pool entry {}...", entry.display());
+                util::remove_any(entry)?;
+                Ok(false)
+            }
+            Self::Mismatch { id, found } => Err(Error::EntryMismatch { id, found }.into()),
+        }
+    }
+}
+
+/// Checks whether the pool `entry` actually contains the toolchain `id`.
+fn check_entry(entry: &Path, id: &str) -> Result<EntryState> {
+    if !entry.try_exists()? {
+        return Ok(EntryState::Missing);
+    }
+    Ok(match IdentifiableToolchain::new(entry) {
+        Ok(found) if found.id() == id => EntryState::Valid,
+        Ok(found) => EntryState::Mismatch {
+            id: id.to_owned(),
+            found: found.id(),
+        },
+        Err(e) => {
+            info!("pool entry {} is broken: {e:#}", entry.display());
+            EntryState::Broken
+        }
+    })
+}
+
+/// Locks the pool `entry` for installation, failing with [`Error::EntryBusy`]
+/// if another transaction is installing it.
+fn lock_pool_entry(entry: &Path) -> Result<Marker> {
+    Marker::acquire_to_hold_resource(entry, Fail::Immediately, None).map_err(|e| match e {
+        acquire::Error::PermanentlyLocked { .. } => {
+            let id = entry.file_name().unwrap().to_string_lossy().into_owned();
+            anyhow::Error::from(e).context(Error::EntryBusy(id))
+        }
+        acquire::Error::Io(e) => e.into(),
+    })
+}
+
+/// Creates the in-flight link of `link` pointing to `target`, declaring the
+/// beginning of the transaction of the `link` toolchain creation.
+///
+/// Returns the in-flight link along with a [`Rollback`] guard removing it.
+/// Fails with [`Error::ToolchainBusy`] if another transaction is in progress.
+pub fn begin_link_transaction(target: &Path, link: &Path) -> Result<(PathBuf, Rollback)> {
+    let link_in_flight = util::with_tmp(link);
+    if let Err(e) = util::soft_link(target, &link_in_flight) {
+        if e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::AlreadyExists)
+        {
+            let toolchain = link.file_name().unwrap().to_string_lossy();
+            return Err(e.context(Error::ToolchainBusy(toolchain.into_owned())));
+        }
+        return Err(e);
+    }
+    let mut rollback = Rollback::new();
+    rollback.push(&link_in_flight);
+    Ok((link_in_flight, rollback))
+}
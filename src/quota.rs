@@ -0,0 +1,150 @@
+//! The pool size quota, enforced by evicting the least recently used pool
+//! entries.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+};
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+use crate::{
+    Ctx, EntryMetadata, Error, LinkChange, Report,
+    config::Config,
+    entry::{self, ENTRY_METADATA},
+    pool, util,
+};
+
+impl Ctx {
+    /// Evicts pool entries other than `keep` until the pool fits in
+    /// [`GcConfig::max_pool_size`](crate::GcConfig::max_pool_size), starting
+    /// with the least recently used unreferenced ones.
+    ///
+    /// The pinned entries and those referenced by in-flight links are never
+    /// evicted. The referenced ones are only evicted if
+    /// [`GcConfig::evict_referenced`](crate::GcConfig::evict_referenced) is
+    /// set, along with their links.
+    ///
+    /// Returns the removed links and pool entries.
+    pub(crate) fn enforce_quota(&self, keep: &OsStr) -> Result<Report> {
+        let config = Config::load(&self.rynzland_home)?.gc;
+        let mut report = Report::default();
+        let Some(max_size) = config.max_pool_size else {
+            return Ok(report);
+        };
+
+        // Now entering the critical section.
+        let pool = self.rustup_home.join("toolchains");
+        let _lock = self.lock_gc()?;
+        let mut size = self.pool_size()?;
+        if size <= max_size {
+            return Ok(report);
+        }
+
+        let mut links = HashMap::<OsString, Vec<String>>::new();
+        let mut in_flight = HashSet::new();
+        let links_dir = self.rynzland_home.join("toolchains");
+        for entry in links_dir.read_dir()? {
+            let path = entry?.path();
+            let Some(id) = util::soft_link_target(&path)
+                .ok()
+                .and_then(|it| it.file_name().map(ToOwned::to_owned))
+            else {
+                continue;
+            };
+            if util::is_tmp(&path) {
+                in_flight.insert(id);
+            } else if let Some(name) = path.file_name() {
+                let name = name.to_string_lossy().into_owned();
+                links.entry(id).or_default().push(name);
+            }
+        }
+
+        let mut candidates = vec![];
+        for entry in pool.read_dir()? {
+            let entry = entry?;
+            let id = entry.file_name();
+            if !entry.file_type()?.is_dir()
+                || util::is_tmp(&id)
+                || id == keep
+                || in_flight.contains(&id)
+                || config.pins.contains(&*id.to_string_lossy())
+            {
+                continue;
+            }
+            let Some(metadata) = EntryMetadata::load(&entry.path())? else {
+                continue;
+            };
+            let referenced = links.contains_key(&id);
+            if referenced && !config.evict_referenced {
+                continue;
+            }
+            let last_used = entry::last_used(&entry.path(), &metadata);
+            candidates.push((referenced, last_used, id));
+        }
+        candidates.sort_unstable();
+
+        for (_, _, id) in candidates {
+            if size <= max_size {
+                break;
+            }
+            let names = links.remove(&id).unwrap_or_default();
+            // NOTE: The links are claimed all at once before being removed, so that
+            // none of them is left dangling if any is busy.
+            let claimed = names
+                .iter()
+                .map(|name| pool::begin_link_transaction(&pool.join(&id), &links_dir.join(name)))
+                .collect::<Result<Vec<_>>>();
+            let claimed = match claimed {
+                Ok(it) => it,
+                Err(e) if matches!(Error::find(&e), Some(Error::ToolchainBusy(_))) => {
+                    info!("toolchain is busy, not evicting {}: {e:#}", id.display());
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            info!(
+                "pool exceeds {}, evicting underlying toolchain {}...",
+                util::human_size(max_size),
+                id.display(),
+            );
+            for name in names {
+                util::soft_unlink(&links_dir.join(&name))?;
+                report.links.push(LinkChange {
+                    toolchain: name,
+                    from: Some(id.to_string_lossy().into_owned()),
+                    to: None,
+                });
+            }
+            // NOTE: The claims are only released once the entry is gone, so that
+            // the evicted links can't be added back to it in the meantime.
+            self.remove_entry(&id)?;
+            drop(claimed);
+            report.removed.push(id.to_string_lossy().into_owned());
+            size = self.pool_size()?;
+        }
+        if size > max_size {
+            warn!(
+                "pool size {} still exceeds the quota of {}",
+                util::human_size(size),
+                util::human_size(max_size),
+            );
+        }
+        Ok(report)
+    }
+
+    /// Returns the total size of the pool entries in bytes, counting the files
+    /// shared among them once.
+    pub(crate) fn pool_size(&self) -> Result<u64> {
+        let mut seen = HashSet::new();
+        let mut size = 0;
+        for entry in self.rustup_home.join("toolchains").read_dir()? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() && entry.path().join(ENTRY_METADATA).exists() {
+                size += util::dir_size(&entry.path(), &mut seen)?;
+            }
+        }
+        Ok(size)
+    }
+}
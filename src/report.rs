@@ -0,0 +1,237 @@
+use std::{process::ExitStatus, str::FromStr};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{IdentifiableToolchain, LinkedToolchain, ToolchainUsage, entry};
+
+/// The output format of the subcommand results.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Human-readable output, where most details are only logged.
+    #[default]
+    Text,
+
+    /// A single JSON object per invocation on stdout.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown format `{s}`, expected `text` or `json`")),
+        }
+    }
+}
+
+/// The structured result of a subcommand, as printed on stdout.
+///
+/// Each variant only carries what the subcommands producing it have to report,
+/// so that e.g. `list` doesn't come with empty link changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Output {
+    /// The changes made by a transaction, e.g. by `add` or `gc`.
+    Report(Report),
+
+    /// The toolchain links listed by `list`.
+    Toolchains { toolchains: Vec<LinkedToolchain> },
+
+    /// The usage statistics listed by `stats`.
+    Usage { usage: Vec<ToolchainUsage> },
+
+    /// The toolchain identified by `id` or `id-chan`.
+    Id { id: String, version: String },
+
+    /// The pool entries pinned by `pin` or unpinned by `unpin`.
+    Pins(PinChange),
+
+    /// The exit status of the shim run by `run`, which is passed on as the
+    /// exit status of the process, with nothing printed since stdout belongs
+    /// to the shim.
+    #[serde(skip)]
+    Exited(ExitStatus),
+}
+
+/// The changes made by a transaction.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Report {
+    /// The toolchain links that have been changed.
+    pub links: Vec<LinkChange>,
+
+    /// The IDs of the pool entries that have been created.
+    pub created: Vec<String>,
+
+    /// The IDs of the pool entries that have been garbage-collected.
+    pub removed: Vec<String>,
+
+    /// The value of `pkg.rust.version` of the toolchain involved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// The changes made to the pins in the configuration.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct PinChange {
+    /// The IDs of the pool entries that have been pinned.
+    pub pinned: Vec<String>,
+
+    /// The IDs of the pool entries that have been unpinned.
+    pub unpinned: Vec<String>,
+}
+
+/// A change of the pool entry referenced by a toolchain link.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LinkChange {
+    /// The (qualified) toolchain name.
+    pub toolchain: String,
+
+    /// The ID referenced before the change, if the link existed.
+    pub from: Option<String>,
+
+    /// The ID referenced after the change, if the link still exists.
+    pub to: Option<String>,
+}
+
+impl Report {
+    /// Appends the changes of `other`, a transaction that has followed this
+    /// one, keeping the version of this one unless it's missing.
+    pub fn merge(&mut self, other: Self) {
+        self.links.extend(other.links);
+        self.created.extend(other.created);
+        self.removed.extend(other.removed);
+        self.version = self.version.take().or(other.version);
+    }
+}
+
+impl From<Report> for Output {
+    fn from(report: Report) -> Self {
+        Self::Report(report)
+    }
+}
+
+impl From<PinChange> for Output {
+    fn from(change: PinChange) -> Self {
+        Self::Pins(change)
+    }
+}
+
+impl From<ExitStatus> for Output {
+    fn from(status: ExitStatus) -> Self {
+        Self::Exited(status)
+    }
+}
+
+impl From<Vec<LinkedToolchain>> for Output {
+    fn from(toolchains: Vec<LinkedToolchain>) -> Self {
+        Self::Toolchains { toolchains }
+    }
+}
+
+impl From<Vec<ToolchainUsage>> for Output {
+    fn from(usage: Vec<ToolchainUsage>) -> Self {
+        Self::Usage { usage }
+    }
+}
+
+impl From<IdentifiableToolchain> for Output {
+    fn from(toolchain: IdentifiableToolchain) -> Self {
+        Self::Id {
+            id: toolchain.id(),
+            version: toolchain.rust_ver,
+        }
+    }
+}
+
+impl Output {
+    /// Renders the output in the given `format`, returning `None` if there is
+    /// nothing to print.
+    pub fn render(&self, format: Format) -> Result<Option<String>> {
+        Ok(match (self, format) {
+            // NOTE: The details of the transactions are only logged in the text format.
+            (Self::Exited(_), _) | (Self::Report(_) | Self::Pins(_), Format::Text) => None,
+            (_, Format::Json) => Some(serde_json::to_string(self)?),
+            (Self::Toolchains { toolchains }, Format::Text) => Some(
+                toolchains
+                    .iter()
+                    .map(|tc| {
+                        let version = tc.version.as_deref().unwrap_or("unknown");
+                        format!("{}\t{}\t{version}", tc.name, tc.id)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            (Self::Usage { usage }, Format::Text) => {
+                let now = entry::unix_now();
+                Some(
+                    usage
+                        .iter()
+                        .map(|tc| {
+                            let last_used = tc.last_used.map_or_else(
+                                || "never".to_owned(),
+                                |it| {
+                                    format!(
+                                        "{} day(s) ago",
+                                        now.saturating_sub(it) / (24 * 60 * 60)
+                                    )
+                                },
+                            );
+                            format!("{}\t{}\t{last_used}", tc.name, tc.count)
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                )
+            }
+            (Self::Id { id, .. }, Format::Text) => Some(id.clone()),
+        }
+        .filter(|it| !it.is_empty()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_json() -> Result<()> {
+        let json = Output::from(Vec::<LinkedToolchain>::new()).render(Format::Json)?;
+        assert_eq!(json.as_deref(), Some(r#"{"toolchains":[]}"#));
+
+        let json = Output::from(Report::default()).render(Format::Json)?;
+        assert_eq!(
+            json.as_deref(),
+            Some(r#"{"links":[],"created":[],"removed":[]}"#),
+        );
+
+        let json = Output::from(PinChange {
+            pinned: vec!["a".into()],
+            ..PinChange::default()
+        })
+        .render(Format::Json)?;
+        assert_eq!(json.as_deref(), Some(r#"{"pinned":["a"],"unpinned":[]}"#));
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge() {
+        let mut report = Report {
+            created: vec!["a".into()],
+            version: Some("1.81.0".into()),
+            ..Report::default()
+        };
+        report.merge(Report {
+            created: vec!["b".into()],
+            removed: vec!["c".into()],
+            version: Some("1.80.0".into()),
+            ..Report::default()
+        });
+        assert_eq!(report.created, ["a", "b"]);
+        assert_eq!(report.removed, ["c"]);
+        assert_eq!(report.version.as_deref(), Some("1.81.0"));
+    }
+}
@@ -1,26 +1,140 @@
-use std::{env::consts::EXE_SUFFIX, path::Path};
+use std::{
+    env::consts::EXE_SUFFIX,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use tracing::{info, warn};
 
-use crate::util::{BUILD_TARGET, download_file};
+use crate::{
+    Ctx, Error,
+    backend::Backend,
+    cache, signal,
+    toolchain::{self, CHANNEL_MANIFEST_SUBPATH, Manifest, ToolchainDesc},
+    util::{self, BUILD_TARGET, CommandExt, Rollback, download_file},
+};
+
+/// The [`Backend`] driving the version-pinned rustup binary at `ctx.rustup`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RustupBackend;
 
 /// Returns the following URL for the official rustup binary:
-/// `https://static.rust-lang.org/rustup/archive/{rustup-version}/{target-triple}/rustup-init[.exe]`
+/// `{dist-server}/rustup/archive/{rustup-version}/{target-triple}/rustup-init[.
+/// exe]`
 ///
 /// See: <https://rust-lang.github.io/rustup/installation/other.html#manual-installation>
-fn rustup_url(version: &str) -> String {
-    format!(
-        "https://static.rust-lang.org/rustup/archive/{version}/{BUILD_TARGET}/rustup-init{EXE_SUFFIX}"
-    )
+fn rustup_url(dist_server: &str, version: &str) -> String {
+    format!("{dist_server}/rustup/archive/{version}/{BUILD_TARGET}/rustup-init{EXE_SUFFIX}")
+}
+
+/// Returns the following URL for the channel manifest of `desc`:
+/// `{dist-server}/dist[/{date}]/channel-rust-{channel}.toml`
+pub fn manifest_url(dist_server: &str, desc: &ToolchainDesc) -> String {
+    let channel = &desc.channel;
+    let date = desc
+        .date
+        .as_ref()
+        .map_or_else(String::new, |date| format!("/{date}"));
+    format!("{dist_server}/dist{date}/channel-rust-{channel}.toml")
 }
 
-pub fn manifest_url(channel: &str) -> String {
-    format!("https://static.rust-lang.org/dist/channel-rust-{channel}.toml")
+/// A private `RUSTUP_HOME` for a single transaction, so that its toolchain
+/// names can never clash with those of concurrent ones. It shares the settings
+/// of `ctx.rustup_home` and the download cache, and is removed on drop.
+struct PrivateHome {
+    path: PathBuf,
+    cache: PathBuf,
+    _rollback: Rollback,
 }
 
-pub fn setup(dest: &Path) -> Result<()> {
+impl PrivateHome {
+    /// Creates the private home for the transaction on the pool entry `dir`,
+    /// which is exclusive to the holder of the entry's lock.
+    fn new(ctx: &Ctx, dir: &Path) -> Result<Self> {
+        let name = dir.file_name().context("expected a pool entry")?;
+        let path = ctx.staging_dir().join(name);
+        // NOTE: Whatever is left here has been left behind by a crashed transaction
+        // on the same entry.
+        util::remove_any(&path)?;
+        let mut rollback = Rollback::new();
+        rollback.push(&path);
+        fs::create_dir_all(path.join("toolchains"))?;
+
+        let settings = ctx.rustup_home.join("settings.toml");
+        if settings.try_exists()? {
+            fs::copy(&settings, path.join("settings.toml"))?;
+        }
+        fs::create_dir(path.join("downloads"))?;
+        Ok(Self {
+            path,
+            cache: ctx.download_cache(),
+            _rollback: rollback,
+        })
+    }
+
+    /// Makes the packages of the (qualified) `components` available to rustup
+    /// from the download cache, downloading the missing ones into the latter.
+    ///
+    /// NOTE: rustup removes the downloaded packages once installed, so they are
+    /// hard-linked into the home instead of sharing the cache directory itself.
+    /// Otherwise, a concurrent installation could lose the files it is about to
+    /// read.
+    fn prefetch(&self, ctx: &Ctx, manifest: &Manifest, components: &[String]) {
+        for comp in components {
+            let Some(&(url, hash)) = manifest.pkg_bins(comp).first() else {
+                continue;
+            };
+            let linked = cache::fetch(ctx, url, hash)
+                .and_then(|it| Ok(fs::hard_link(it, self.path.join("downloads").join(hash))?));
+            // NOTE: This is merely an optimization, as rustup would download the
+            // package itself otherwise.
+            if let Err(e) = linked {
+                warn!("failed to prefetch `{comp}`: {e:#}");
+            }
+        }
+    }
+
+    fn command(&self, ctx: &Ctx) -> Command {
+        let mut cmd = Command::new(&ctx.rustup);
+        ctx.set_env_local(&mut cmd).env("RUSTUP_HOME", &self.path);
+        cmd
+    }
+}
+
+impl Drop for PrivateHome {
+    fn drop(&mut self) {
+        // Packages left over by a failed installation can be reused by a retry.
+        let shared = fs::create_dir_all(&self.cache)
+            .and_then(|()| link_downloads(&self.path.join("downloads"), &self.cache));
+        if let Err(e) = shared {
+            warn!("failed to share downloads: {e}");
+        }
+    }
+}
+
+/// Hard-links the completely downloaded packages in `src` into `dst`, keeping
+/// the ones already there.
+fn link_downloads(src: &Path, dst: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        // NOTE: The packages are named after their hashes, whereas the incomplete
+        // ones have the `.partial` extension.
+        if !entry.file_type()?.is_file() || Path::new(&entry.file_name()).extension().is_some() {
+            continue;
+        }
+        match fs::hard_link(entry.path(), dst.join(entry.file_name())) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub fn setup(dist_server: &str, dest: &Path) -> Result<()> {
     // Pin a pre-XDG rustup to simplify path config.
-    let url = rustup_url("1.28.2");
+    let url = rustup_url(dist_server, "1.28.2");
     download_file(&url, dest)?;
 
     #[cfg(unix)]
@@ -35,3 +149,147 @@ pub fn setup(dest: &Path) -> Result<()> {
 
     Ok(())
 }
+
+impl Backend for RustupBackend {
+    fn setup(&self, ctx: &Ctx) -> Result<()> {
+        if ctx.rustup.try_exists()? {
+            info!("rustup already set up, skipping...");
+        } else {
+            info!("setting up rustup...");
+            setup(&ctx.dist_server, &ctx.rustup)?;
+        }
+        info!("setting up FS link to local rustup...");
+        let local_cargo_bin = ctx.cargo_home.join("bin");
+        fs::create_dir_all(&local_cargo_bin)?;
+
+        let local_rustup_link = local_cargo_bin.join("rustup");
+        if !local_rustup_link.try_exists()? {
+            #[cfg(unix)]
+            util::soft_link(&ctx.rustup, &local_rustup_link)?;
+
+            #[cfg(windows)]
+            fs::hard_link(&*ctx.rustup, &local_rustup_link)?;
+        }
+
+        for home in [&ctx.rustup_home, &ctx.rynzland_home] {
+            Command::new(&ctx.rustup)
+                .env("RUSTUP_HOME", home)
+                .args(["set", "profile", "minimal"])
+                .run_checked()?;
+
+            Command::new(&ctx.rustup)
+                .env("RUSTUP_HOME", home)
+                .args(["set", "auto-install", "disable"])
+                .run_checked()?;
+
+            Command::new(&ctx.rustup)
+                .env("RUSTUP_HOME", home)
+                .args(["set", "auto-self-update", "disable"])
+                .run_checked()?;
+        }
+        Ok(())
+    }
+
+    fn fetch_manifest(&self, ctx: &Ctx, toolchain: &ToolchainDesc, dest: &Path) -> Result<()> {
+        let manifest_url = manifest_url(&ctx.dist_server, toolchain);
+        info!("downloading manifest from {manifest_url}...");
+        download_file(&manifest_url, dest).map_err(|e| {
+            if util::is_not_found(&e) {
+                e.context(Error::ManifestNotFound(toolchain.to_string()))
+            } else {
+                e
+            }
+        })
+    }
+
+    fn install(
+        &self,
+        ctx: &Ctx,
+        toolchain: &ToolchainDesc,
+        components: &[String],
+        dest: &Path,
+    ) -> Result<()> {
+        // NOTE: The toolchain is staged in a private home, so concurrent installations
+        // of the same source can't interfere with each other, and whatever is left
+        // behind by a failure goes away along with the home.
+        let home = PrivateHome::new(ctx, dest)?;
+        let manifest_path = home.path.join("channel-manifest.toml");
+        self.fetch_manifest(ctx, toolchain, &manifest_path)?;
+        let manifest = Manifest::load(&manifest_path)?;
+        let packages = toolchain::default_components()
+            .chain(
+                components
+                    .iter()
+                    .map(|it| manifest.normalize(&util::qualify_with_target(it))),
+            )
+            .collect::<Vec<_>>();
+        home.prefetch(ctx, &manifest, &packages);
+
+        let name = toolchain.to_string();
+        home.command(ctx)
+            .args(["install", &name])
+            .args(components.iter().flat_map(|c| ["--component", c]))
+            .run_streaming()?;
+        signal::check()?;
+        // NOTE: The staging directory is next to the pool, so this is atomic.
+        fs::rename(home.path.join("toolchains").join(&name), dest)?;
+        Ok(())
+    }
+
+    fn modify_components(
+        &self,
+        ctx: &Ctx,
+        dir: &Path,
+        components: &[String],
+        add: bool,
+    ) -> Result<()> {
+        let op = if add { "add" } else { "remove" };
+
+        // NOTE: rustup only modifies the components of toolchains with an official
+        // name, so `dir` is made available under one in a private home of its own.
+        // The name itself is irrelevant, as rustup goes by the manifest in `dir`.
+        let home = PrivateHome::new(ctx, dir)?;
+        if add {
+            let manifest = Manifest::load(&dir.join(*CHANNEL_MANIFEST_SUBPATH))?;
+            let packages = components
+                .iter()
+                .map(|it| manifest.normalize(&util::qualify_with_target(it)))
+                .collect::<Vec<_>>();
+            home.prefetch(ctx, &manifest, &packages);
+        }
+        let toolchain_name = util::qualify_with_target("stable");
+        util::soft_link(dir, &home.path.join("toolchains").join(&*toolchain_name))?;
+        home.command(ctx)
+            .env("RUSTUP_TOOLCHAIN", &*toolchain_name)
+            .arg("component")
+            .arg(op)
+            .args(components)
+            .run_streaming()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toolchain::ToolchainName;
+
+    #[test]
+    fn manifest_urls() {
+        let url = |name: &str| {
+            let name = name.parse::<ToolchainName>().unwrap();
+            manifest_url("https://example.com", name.desc().unwrap())
+        };
+        assert_eq!(
+            url("stable"),
+            "https://example.com/dist/channel-rust-stable.toml",
+        );
+        assert_eq!(
+            url("1.80-aarch64-apple-darwin"),
+            "https://example.com/dist/channel-rust-1.80.toml",
+        );
+        assert_eq!(
+            url("nightly-2025-06-01"),
+            "https://example.com/dist/2025-06-01/channel-rust-nightly.toml",
+        );
+    }
+}
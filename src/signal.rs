@@ -0,0 +1,49 @@
+//! Cooperative handling of termination signals (Ctrl-C and friends).
+//!
+//! Instead of dying on the spot, the process raises a flag that is polled by
+//! the running transaction and by child processes spawned via
+//! [`CommandExt::run_checked`](crate::util::CommandExt::run_checked), so that
+//! in-flight links and half-installed toolchains can be rolled back before
+//! exiting.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use tracing::warn;
+
+use crate::Error;
+
+/// The conventional exit code of a process terminated by `SIGINT`.
+pub const EXIT_INTERRUPTED: u8 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Installs the process-wide signal handler.
+///
+/// The first signal only marks the process as interrupted, leaving it to the
+/// current transaction to roll back and bail out. A second signal exits
+/// immediately.
+pub fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_INTERRUPTED.into());
+        }
+        warn!("interrupted, rolling back... (press Ctrl-C again to force quit)");
+    })?;
+    Ok(())
+}
+
+/// Returns whether a termination signal has been received.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Bails out if a termination signal has been received.
+///
+/// This is meant to be called between the steps of a transaction.
+pub fn check() -> Result<()> {
+    if is_interrupted() {
+        return Err(Error::Interrupted.into());
+    }
+    Ok(())
+}
@@ -1,14 +1,17 @@
+mod dist;
+mod fake;
+mod fault;
 mod prelude;
+mod stress;
 
-use std::{collections::HashSet, thread, time::Duration};
+use std::{collections::HashSet, fs, thread, time::Duration};
 
 use gix_lock::acquire::Fail;
 use prelude::*;
 
 use crate::{
-    AddSubcmd, CompAddSubcmd, CompRmSubcmd, NukeSubcmd, Result, RmSubCmd,
-    toolchain::{self, IdentifiableToolchain},
-    util,
+    AddSubcmd, CompAddSubcmd, CompRmSubcmd, Config, EntryMetadata, Error, GcSubcmd, NukeSubcmd,
+    Pool, Result, RmSubCmd, RunSubCmd, StatsSubcmd, toolchain::IdentifiableToolchain, util,
 };
 
 #[test]
@@ -49,26 +52,83 @@ fn toolchain_id() -> Result<()> {
     let patch = "1.92.0";
 
     // Add a versioned-based toolchain.
-    AddSubcmd {
-        toolchain: minor.into(),
-        source: None,
-    }
-    .run(&ctx.app_ctx())?;
+    add(&ctx.app_ctx(), minor, None)?;
 
     let tc_path = rynzland_home
         .join("toolchains")
         .join(util::qualify_with_target(minor).as_ref());
     let id_from_disk = IdentifiableToolchain::new(&tc_path)?.id();
-    let id_from_remote = toolchain::resolve_channel(patch, &[])?.id();
+    let id_from_remote = Pool::new(ctx.app_ctx()).identify_channel(patch, &[])?.id();
     assert_eq!(id_from_disk, id_from_remote);
 
-    let id_from_remote_nightly = toolchain::resolve_channel("nightly", &[])?.id();
+    let id_from_remote_nightly = Pool::new(ctx.app_ctx())
+        .identify_channel("nightly", &[])?
+        .id();
     assert_ne!(id_from_disk, id_from_remote_nightly);
 
     drop(ctx);
     Ok(())
 }
 
+#[test]
+fn dated_nightlies() -> Result<()> {
+    let ctx = Ctx::setup()?;
+    let app_ctx = ctx.app_ctx();
+
+    // Nightlies whose builds failed are archived with the previous versions.
+    let dist = ctx.dist();
+    dist.set_channel("nightly-2025-06-01", "1.89.0-nightly");
+    dist.set_channel("nightly-2025-06-02", "1.89.0-nightly");
+    dist.set_channel("nightly-2025-06-03", "1.90.0-nightly");
+
+    for date in ["2025-06-01", "2025-06-02", "2025-06-03"] {
+        add(&app_ctx, &format!("nightly-{date}"), None)?;
+    }
+
+    // The rebuilds of the same version share their entry by default, even
+    // though their manifests and tarballs differ.
+    let underlying = |date| resolve_link(&ctx.link(&format!("nightly-{date}")));
+    assert_eq!(underlying("2025-06-01")?, underlying("2025-06-02")?);
+    assert_ne!(underlying("2025-06-02")?, underlying("2025-06-03")?);
+    let shared = underlying("2025-06-01")?;
+    let metadata = EntryMetadata::load(&shared)?.expect("metadata should exist");
+    assert_eq!(metadata.date.as_deref(), Some("2025-06-01"));
+
+    let pool = Pool::new(app_ctx.clone());
+    let id = IdentifiableToolchain::new(&underlying("2025-06-03")?)?.id();
+    assert_eq!(id, pool.identify_channel("nightly-2025-06-03", &[])?.id());
+    assert_ne!(id, pool.identify_channel("nightly", &[])?.id());
+    let err = pool
+        .identify_channel("nightly-2025-05-31", &[])
+        .unwrap_err();
+    assert!(
+        matches!(Error::find(&err), Some(Error::ManifestNotFound(_))),
+        "{err:?}"
+    );
+
+    // Strict IDs tell the rebuilds apart, while still reusing the entry of the
+    // very same build.
+    Config {
+        strict_ids: true,
+        ..Config::default()
+    }
+    .write(&app_ctx.rynzland_home)?;
+    let report = add(&app_ctx, "same", Some("nightly-2025-06-01"))?;
+    assert!(report.created.is_empty(), "{report:?}");
+    assert_eq!(resolve_link(&ctx.link("same"))?, shared);
+
+    let report = add(&app_ctx, "rebuilt", Some("nightly-2025-06-02"))?;
+    assert_eq!(report.created.len(), 1);
+    let rebuilt = resolve_link(&ctx.link("rebuilt"))?;
+    assert_ne!(rebuilt, shared);
+    let metadata = EntryMetadata::load(&rebuilt)?.expect("metadata should exist");
+    assert_eq!(metadata.date.as_deref(), Some("2025-06-02"));
+    assert_consistent(&app_ctx)?;
+
+    drop(ctx);
+    Ok(())
+}
+
 #[test]
 fn toolchain_management() -> Result<()> {
     let ctx = Ctx::setup()?;
@@ -80,11 +140,7 @@ fn toolchain_management() -> Result<()> {
     let chan = "stable";
 
     // Add a versioned-based toolchain.
-    AddSubcmd {
-        toolchain: ver.into(),
-        source: None,
-    }
-    .run(&ctx.app_ctx())?;
+    add(&ctx.app_ctx(), ver, None)?;
 
     let tc_link = rynzland_home
         .join("toolchains")
@@ -108,18 +164,14 @@ fn toolchain_management() -> Result<()> {
     let id_from_disk = IdentifiableToolchain::new(&underlying_path)?.id();
 
     // Check identification match (remote vs local)
-    let id_from_remote = toolchain::resolve_channel(ver, &[])?.id();
+    let id_from_remote = Pool::new(ctx.app_ctx()).identify_channel(ver, &[])?.id();
     assert_eq!(
         id_from_disk, id_from_remote,
         "local and remote IDs should match"
     );
 
     // Add a channel-based toolchain pointing to same underlying toolchain.
-    AddSubcmd {
-        toolchain: chan.into(),
-        source: Some(ver.into()),
-    }
-    .run(&ctx.app_ctx())?;
+    add(&ctx.app_ctx(), chan, Some(ver))?;
 
     let chan_link = rynzland_home
         .join("toolchains")
@@ -147,7 +199,7 @@ fn toolchain_management() -> Result<()> {
         "channel-based toolchain should be gone"
     );
     // Underlying should still exist because the other toolchain still uses it.
-    assert!(underlying_path.exists(), "underlying should still exist",);
+    assert!(underlying_path.exists(), "underlying should still exist");
 
     // Remove final ref.
     RmSubCmd {
@@ -175,11 +227,7 @@ fn update_toolchain_gc() -> Result<()> {
     let v2 = "1.92.0";
 
     // Add stable from 1.91.0.
-    AddSubcmd {
-        toolchain: stable.into(),
-        source: Some(v1.into()),
-    }
-    .run(&ctx.app_ctx())?;
+    add(&ctx.app_ctx(), stable, Some(v1))?;
 
     let stable_link = rynzland_home
         .join("toolchains")
@@ -194,11 +242,7 @@ fn update_toolchain_gc() -> Result<()> {
     assert!(underlying_v1.exists(), "v1 toolchain should exist");
 
     // Update stable to 1.92.0.
-    AddSubcmd {
-        toolchain: stable.into(),
-        source: Some(v2.into()),
-    }
-    .run(&ctx.app_ctx())?;
+    add(&ctx.app_ctx(), stable, Some(v2))?;
 
     let link_target_v2 = util::soft_link_target(&stable_link)?;
     let underlying_v2 = if link_target_v2.is_relative() {
@@ -222,6 +266,34 @@ fn update_toolchain_gc() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn comp_renames() -> Result<()> {
+    let ctx = Ctx::setup()?;
+    let app_ctx = ctx.app_ctx();
+
+    AddSubcmd {
+        toolchain: "stable".into(),
+        source: None,
+        components: vec!["clippy-preview".into()],
+        allow_downgrade: false,
+    }
+    .run(&app_ctx)?;
+    add(&app_ctx, "1.81.0", None)?;
+    CompAddSubcmd {
+        toolchain: "1.81.0".into(),
+        components: vec!["clippy".into()],
+    }
+    .run(&app_ctx)?;
+
+    let underlying = resolve_link(&ctx.link("stable"))?;
+    assert_eq!(resolve_link(&ctx.link("1.81.0"))?, underlying);
+    let clippy = format!("clippy{}", std::env::consts::EXE_SUFFIX);
+    assert!(underlying.join("bin").join(clippy).exists());
+
+    drop(ctx);
+    Ok(())
+}
+
 #[test]
 fn comp_add_rm() -> Result<()> {
     let ctx = Ctx::setup()?;
@@ -231,11 +303,7 @@ fn comp_add_rm() -> Result<()> {
     let toolchain_name = "1.78";
 
     // Add stable toolchain
-    AddSubcmd {
-        toolchain: toolchain_name.into(),
-        source: None,
-    }
-    .run(&ctx.app_ctx())?;
+    add(&ctx.app_ctx(), toolchain_name, None)?;
 
     let link_path = rynzland_home
         .join("toolchains")
@@ -323,6 +391,8 @@ fn concurrent_add_same() -> Result<()> {
             AddSubcmd {
                 toolchain,
                 source: None,
+                components: vec![],
+                allow_downgrade: false,
             }
             .run(&app_ctx)
         });
@@ -374,6 +444,8 @@ fn concurrent_add_same_underlying() -> Result<()> {
             AddSubcmd {
                 toolchain,
                 source: Some(ver),
+                components: vec![],
+                allow_downgrade: false,
             }
             .run(&app_ctx)
         });
@@ -418,11 +490,7 @@ fn concurrent_rm_same() -> Result<()> {
     let thread_count = 5;
 
     // First add the toolchain.
-    AddSubcmd {
-        toolchain: toolchain.into(),
-        source: None,
-    }
-    .run(&app_ctx)?;
+    add(&app_ctx, toolchain, None)?;
 
     let link_path = rynzland_home
         .join("toolchains")
@@ -461,11 +529,7 @@ fn concurrent_rm_same_underlying() -> Result<()> {
     let toolchains = ["stable", "1.79", ver];
 
     for toolchain in toolchains {
-        AddSubcmd {
-            toolchain: toolchain.into(),
-            source: Some(ver.into()),
-        }
-        .run(&app_ctx)?;
+        add(&app_ctx, toolchain, Some(ver))?;
     }
 
     let mut underlying_toolchains = HashSet::new();
@@ -536,11 +600,7 @@ fn concurrent_comp_rm_same_target() -> Result<()> {
     let toolchains = ["stable", "1.80", ver];
 
     for toolchain in toolchains {
-        AddSubcmd {
-            toolchain: toolchain.into(),
-            source: Some(ver.into()),
-        }
-        .run(&app_ctx)?;
+        add(&app_ctx, toolchain, Some(ver))?;
     }
 
     let mut underlying_toolchains = HashSet::new();
@@ -594,3 +654,188 @@ fn concurrent_comp_rm_same_target() -> Result<()> {
     drop(ctx);
     Ok(())
 }
+
+#[test]
+fn concurrent_comp_add_different() -> Result<()> {
+    let ctx = Ctx::setup()?;
+    let app_ctx = ctx
+        .app_ctx()
+        .with_gc_lock_backoff(Fail::AfterDurationWithBackoff(Duration::from_mins(1)));
+    let toolchains = ["1.78", "1.80.0"];
+
+    for toolchain in toolchains {
+        add(&app_ctx, toolchain, None)?;
+    }
+
+    let handles = toolchains.map(|toolchain| {
+        let app_ctx = app_ctx.clone();
+        thread::spawn(move || {
+            CompAddSubcmd {
+                toolchain: toolchain.into(),
+                components: vec!["clippy".into()],
+            }
+            .run(&app_ctx)
+        })
+    });
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+
+    let clippy = format!("clippy{}", std::env::consts::EXE_SUFFIX);
+    for toolchain in toolchains {
+        let underlying = resolve_link(&ctx.link(toolchain))?;
+        assert!(underlying.join("bin").join(&clippy).exists());
+    }
+    let staging = ctx.home().join("rustup_home").join("staging");
+    assert_eq!(
+        staging.read_dir()?.count(),
+        0,
+        "private homes should have been cleaned up",
+    );
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn concurrent_add_same_source() -> Result<()> {
+    let ctx = Ctx::setup()?;
+    let app_ctx = ctx
+        .app_ctx()
+        .with_gc_lock_backoff(Fail::AfterDurationWithBackoff(Duration::from_mins(1)));
+    let ver = "1.80.0";
+
+    // NOTE: These have different IDs, so nothing keeps their installers apart but
+    // the private homes they are staged in.
+    let handles =
+        [("stable", vec![]), ("1.80", vec!["clippy".to_owned()])].map(|(toolchain, components)| {
+            let app_ctx = app_ctx.clone();
+            thread::spawn(move || {
+                AddSubcmd {
+                    toolchain: toolchain.into(),
+                    source: Some(ver.into()),
+                    components,
+                    allow_downgrade: false,
+                }
+                .run(&app_ctx)
+            })
+        });
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+
+    assert_ne!(
+        resolve_link(&ctx.link("stable"))?,
+        resolve_link(&ctx.link("1.80"))?,
+    );
+    let pool = ctx.home().join("rustup_home").join("toolchains");
+    assert!(!pool.join(util::qualify_with_target(ver).as_ref()).exists());
+    assert_consistent(&app_ctx)?;
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn download_cache() -> Result<()> {
+    let ctx = Ctx::setup()?;
+    let app_ctx = ctx.app_ctx();
+    let ver = "1.80.0";
+
+    for (toolchain, components) in [("stable", vec![]), ("1.80", vec!["clippy".to_owned()])] {
+        AddSubcmd {
+            toolchain: toolchain.into(),
+            source: Some(ver.into()),
+            components,
+            allow_downgrade: false,
+        }
+        .run(&app_ctx)?;
+    }
+    assert_ne!(
+        resolve_link(&ctx.link("stable"))?,
+        resolve_link(&ctx.link("1.80"))?,
+    );
+    let std = format!("rust-std-{ver}-{}.tar.gz", util::BUILD_TARGET);
+    assert_eq!(
+        ctx.dist().hits(&std),
+        1,
+        "`rust-std` should be downloaded once"
+    );
+    assert_eq!(
+        ctx.dist()
+            .hits(&format!("clippy-{ver}-{}.tar.gz", util::BUILD_TARGET)),
+        1
+    );
+
+    // Packages of the pool entries are kept, however old they are.
+    let cache = ctx.home().join("rynzland_home").join("cache");
+    let long_ago = std::time::SystemTime::UNIX_EPOCH;
+    for entry in cache.read_dir()? {
+        fs::File::open(entry?.path())?.set_modified(long_ago)?;
+    }
+    let cached = cache.read_dir()?.count();
+    GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    assert_eq!(cache.read_dir()?.count(), cached);
+
+    RmSubCmd {
+        toolchain: "1.80".into(),
+    }
+    .run(&app_ctx)?;
+    GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    assert_eq!(
+        cache.read_dir()?.count(),
+        cached - 1,
+        "the `clippy` package should be gone along with its last entry",
+    );
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn run_passes_exit_status() -> Result<()> {
+    let ctx = Ctx::setup()?;
+    let app_ctx = ctx.app_ctx();
+    add(&app_ctx, "stable", None)?;
+
+    let run = |args: &[&str]| {
+        RunSubCmd {
+            shim: "cargo".into(),
+            toolchain: Some("stable".into()),
+            args: args.iter().map(|&it| it.into()).collect(),
+        }
+        .run(&app_ctx)
+    };
+    assert!(run(&["build"])?.success());
+    // A failing shim is not a failure of rynzland's own.
+    assert_eq!(run(&["--exit=42"])?.code(), Some(42));
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn run_records_use() -> Result<()> {
+    let ctx = Ctx::setup()?;
+    let app_ctx = ctx.app_ctx();
+
+    add(&app_ctx, "stable", None)?;
+    let last_used = resolve_link(&ctx.link("stable"))?.join(crate::entry::LAST_USED);
+    assert!(!last_used.exists());
+
+    RunSubCmd {
+        shim: "rustc".into(),
+        toolchain: Some("stable".into()),
+        args: vec!["--version".into()],
+    }
+    .run(&app_ctx)?;
+    assert!(last_used.exists(), "the use should have been recorded");
+
+    let usage = StatsSubcmd {}.run(&app_ctx)?;
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0].count, 1);
+    assert!(usage[0].last_used.is_some());
+
+    drop(ctx);
+    Ok(())
+}
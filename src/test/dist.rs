@@ -0,0 +1,446 @@
+//! A local dist server for testing the real rustup binary without network
+//! access.
+//!
+//! The server mimics the layout of `static.rust-lang.org`, serving synthetic
+//! channel manifests, tiny component tarballs in the rust-installer format,
+//! and the rustup binary found on the host as `rustup-init`.
+
+use std::{
+    collections::HashMap,
+    env::{self, consts::EXE_SUFFIX},
+    fmt::Write as _,
+    fs,
+    io::{BufRead, BufReader, Write as _},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
+
+use anyhow::{Context, Result};
+use flate2::{Compression, write::GzEncoder};
+use sha2::{Digest, Sha256};
+
+use crate::{toolchain::ToolchainName, util::BUILD_TARGET};
+
+/// The value of `date` in the manifests served for undated channels, which
+/// is also the build date in every version string.
+pub const DATE: &str = "2024-09-05";
+
+/// The packages of every release archived on `date`, along with the files
+/// they install.
+///
+/// The tools print the `date` as well, so that the same version archived on
+/// different days is made of different tarballs, like a rebuild.
+fn packages(ver: &str, date: &str) -> Vec<Package> {
+    let tool = |name: &'static str| Package {
+        name,
+        target: Some(BUILD_TARGET),
+        extension: false,
+        files: vec![(
+            format!("bin/{name}{EXE_SUFFIX}"),
+            // NOTE: `--exit=<code>` makes the tool fail, for testing how it's run.
+            format!(
+                "#!/bin/sh\ncase \"$1\" in --exit=*) exit \"${{1#--exit=}}\" ;; esac\necho '{name} {ver} ({date})'\n"
+            ),
+        )],
+    };
+    let mut pkgs = vec![
+        tool("rustc"),
+        tool("cargo"),
+        Package {
+            name: "rust-std",
+            target: Some(BUILD_TARGET),
+            extension: false,
+            files: vec![(
+                format!("lib/rustlib/{BUILD_TARGET}/lib/libstd.rlib"),
+                String::new(),
+            )],
+        },
+        Package {
+            extension: true,
+            ..tool("clippy")
+        },
+        Package {
+            extension: true,
+            ..tool("rustfmt")
+        },
+        Package {
+            name: "rust-src",
+            target: None,
+            extension: true,
+            files: vec![(
+                "lib/rustlib/src/rust/library/std/src/lib.rs".into(),
+                String::new(),
+            )],
+        },
+    ];
+    if BUILD_TARGET.ends_with("-pc-windows-gnu") {
+        pkgs.push(Package {
+            name: "rust-mingw",
+            target: Some(BUILD_TARGET),
+            extension: false,
+            files: vec![(
+                format!("lib/rustlib/{BUILD_TARGET}/lib/self-contained/crt2.o"),
+                String::new(),
+            )],
+        });
+    }
+    pkgs
+}
+
+struct Package {
+    name: &'static str,
+
+    /// The target of the package, or `None` if it is target-independent.
+    target: Option<&'static str>,
+
+    /// Whether the package is an optional extension of `pkg.rust`.
+    extension: bool,
+
+    /// The paths and contents of the files installed.
+    files: Vec<(String, String)>,
+}
+
+impl Package {
+    fn target(&self) -> &'static str {
+        self.target.unwrap_or("*")
+    }
+
+    fn file_name(&self, ver: &str) -> String {
+        let name = self.name;
+        self.target.map_or_else(
+            || format!("{name}-{ver}.tar.gz"),
+            |target| format!("{name}-{ver}-{target}.tar.gz"),
+        )
+    }
+
+    /// Builds the package tarball in the rust-installer v3 format.
+    fn tarball(&self, ver: &str) -> Result<Vec<u8>> {
+        let root = self.file_name(ver);
+        let root = root.trim_end_matches(".tar.gz");
+        let name = self.name;
+
+        let mut manifest_in = String::new();
+        for (path, _) in &self.files {
+            writeln!(manifest_in, "file:{path}")?;
+        }
+        let mut entries = vec![
+            (format!("{root}/rust-installer-version"), "3\n".to_owned()),
+            (format!("{root}/components"), format!("{name}\n")),
+            (format!("{root}/{name}/manifest.in"), manifest_in),
+        ];
+        for (path, contents) in &self.files {
+            entries.push((format!("{root}/{name}/{path}"), contents.clone()));
+        }
+
+        let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::fast()));
+        for (path, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append_data(&mut header, path, contents.as_bytes())?;
+        }
+        Ok(builder.into_inner()?.finish()?)
+    }
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .fold(String::new(), |mut acc, b| {
+            _ = write!(acc, "{b:02x}");
+            acc
+        })
+}
+
+/// A running local dist server, which shuts down on drop.
+///
+/// Channels, including dated ones like `nightly-2025-06-01` served from the
+/// archives, resolve to the versions registered via
+/// [`DistServer::set_channel`], while version numbers like `1.80` or
+/// `1.80.0` resolve to themselves.
+#[derive(Debug)]
+pub struct DistServer {
+    addr: SocketAddr,
+    state: Arc<State>,
+    shutdown: Arc<AtomicBool>,
+}
+
+#[derive(Debug)]
+struct State {
+    url: String,
+    rustup_init: PathBuf,
+    channels: Mutex<HashMap<String, String>>,
+
+    /// The published tarballs, keyed by their URL paths.
+    files: Mutex<HashMap<String, Arc<Vec<u8>>>>,
+
+    /// How many times each tarball has been served, keyed by its file name.
+    hits: Mutex<HashMap<String, usize>>,
+}
+
+impl DistServer {
+    /// Starts a server on a random local port.
+    pub fn start() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(State {
+            url: format!("http://{addr}"),
+            rustup_init: find_rustup_init()?,
+            channels: Mutex::default(),
+            files: Mutex::default(),
+            hits: Mutex::default(),
+        });
+        let this = Self {
+            addr,
+            state: Arc::clone(&state),
+            shutdown: Arc::default(),
+        };
+        this.set_channel("stable", "1.81.0");
+        this.set_channel("beta", "1.82.0-beta.1");
+        this.set_channel("nightly", "1.83.0-nightly");
+
+        let shutdown = Arc::clone(&this.shutdown);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if shutdown.load(Ordering::Acquire) {
+                    break;
+                }
+                let Ok(stream) = stream else { continue };
+                let state = Arc::clone(&state);
+                thread::spawn(move || state.serve(stream));
+            }
+        });
+        Ok(this)
+    }
+
+    /// Returns the root URL, to be used as `RUSTUP_DIST_SERVER`.
+    pub fn url(&self) -> &str {
+        &self.state.url
+    }
+
+    /// Returns how many times the tarball `file_name`, such as
+    /// `rust-src-1.80.0.tar.gz`, has been served.
+    pub fn hits(&self, file_name: &str) -> usize {
+        self.state
+            .hits
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(file_name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Makes `channel` resolve to the Rust version `version`.
+    pub fn set_channel(&self, channel: &str, version: &str) {
+        self.state
+            .channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(channel.to_owned(), version.to_owned());
+    }
+}
+
+impl Drop for DistServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Release);
+        // Wake up the accepting thread so that it notices the shutdown.
+        _ = TcpStream::connect(self.addr);
+    }
+}
+
+/// Returns the channel of the manifest at `path` ending with `suffix`, which
+/// is dated like `nightly-2025-06-01` if the manifest is an archived one.
+fn manifest_channel(path: &str, suffix: &str) -> Option<String> {
+    let path = path.strip_prefix("/dist/")?.strip_suffix(suffix)?;
+    match path.split_once('/') {
+        Some((date, file)) => Some(format!("{}-{date}", file.strip_prefix("channel-rust-")?)),
+        None => Some(path.strip_prefix("channel-rust-")?.to_owned()),
+    }
+}
+
+/// Returns the rustup binary to serve as `rustup-init`, which is taken from
+/// `RYNZLAND_TEST_RUSTUP` if set, or from the rustup installation on the host
+/// otherwise.
+fn find_rustup_init() -> Result<PathBuf> {
+    if let Some(path) = env::var_os("RYNZLAND_TEST_RUSTUP") {
+        return Ok(path.into());
+    }
+    env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::home_dir().map(|it| it.join(".cargo")))
+        .map(|it| it.join("bin").join(format!("rustup{EXE_SUFFIX}")))
+        .filter(|it| it.is_file())
+        .context("failed to find rustup, please set `RYNZLAND_TEST_RUSTUP`")
+}
+
+impl State {
+    fn serve(&self, stream: TcpStream) {
+        if let Err(e) = self.try_serve(stream) {
+            eprintln!("dist server: {e:?}");
+        }
+    }
+
+    fn try_serve(&self, mut stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let path = parts.next().unwrap_or_default();
+        let body = self.get(path)?;
+        if body.is_none() {
+            eprintln!("dist server: not found: {path}");
+        }
+        let status = if body.is_some() {
+            "200 OK"
+        } else {
+            "404 Not Found"
+        };
+        let body = body.unwrap_or_default();
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len(),
+        )?;
+        if method != "HEAD" {
+            stream.write_all(&body)?;
+        }
+        stream.flush()?;
+        Ok(())
+    }
+
+    fn get(&self, path: &str) -> Result<Option<Arc<Vec<u8>>>> {
+        if path.starts_with("/rustup/archive/")
+            && path.ends_with(&format!("/{BUILD_TARGET}/rustup-init{EXE_SUFFIX}"))
+        {
+            return Ok(Some(Arc::new(fs::read(&self.rustup_init)?)));
+        }
+        if let Some(channel) = manifest_channel(path, ".toml.sha256") {
+            let Some(manifest) = self.publish(&channel)? else {
+                return Ok(None);
+            };
+            let hash = sha256(manifest.as_bytes());
+            let file_name = path.rsplit('/').next().unwrap().strip_suffix(".sha256");
+            let body = format!("{hash}  {}\n", file_name.unwrap());
+            return Ok(Some(Arc::new(body.into_bytes())));
+        }
+        if let Some(channel) = manifest_channel(path, ".toml") {
+            return Ok(self.publish(&channel)?.map(|it| Arc::new(it.into_bytes())));
+        }
+        let file = self
+            .files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(path)
+            .cloned();
+        if file.is_some() {
+            let file_name = path.rsplit('/').next().unwrap_or_default();
+            *self
+                .hits
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(file_name.to_owned())
+                .or_default() += 1;
+        }
+        Ok(file)
+    }
+
+    /// Returns the Rust version `channel` resolves to, if any.
+    fn resolve(&self, channel: &str) -> Option<String> {
+        let registered = self
+            .channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(channel)
+            .cloned();
+        if registered.is_some() {
+            return registered;
+        }
+        if !channel.split('.').all(|it| it.parse::<u32>().is_ok()) {
+            return None;
+        }
+        match channel.matches('.').count() {
+            1 => Some(format!("{channel}.0")),
+            2 => Some(channel.to_owned()),
+            _ => None,
+        }
+    }
+
+    /// Publishes the tarballs of the release `channel` resolves to under the
+    /// archive date of `channel`, or [`DATE`] if it is undated, returning its
+    /// channel manifest.
+    fn publish(&self, channel: &str) -> Result<Option<String>> {
+        let Some(ver) = self.resolve(channel) else {
+            return Ok(None);
+        };
+        let date = channel
+            .parse::<ToolchainName>()
+            .ok()
+            .and_then(|it| it.desc().ok()?.date.clone())
+            .unwrap_or_else(|| DATE.to_owned());
+        let rust_ver = format!("{ver} (fixture {DATE})");
+        let url = &self.url;
+        let pkgs = packages(&ver, &date);
+
+        let mut manifest = format!("manifest-version = \"2\"\ndate = \"{date}\"\n");
+        let mut rust = format!(
+            "[pkg.rust]\nversion = \"{rust_ver}\"\n\n\
+             [pkg.rust.target.{BUILD_TARGET}]\navailable = true\n\
+             url = \"{url}/dist/{date}/rust-{ver}-{BUILD_TARGET}.tar.gz\"\n\
+             hash = \"{}\"\n",
+            sha256(b""),
+        );
+        for pkg in &pkgs {
+            let file_name = pkg.file_name(&ver);
+            let tarball = pkg.tarball(&ver)?;
+            let hash = sha256(&tarball);
+            self.files
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(format!("/dist/{date}/{file_name}"), Arc::new(tarball));
+
+            let (name, target) = (pkg.name, pkg.target());
+            write!(
+                manifest,
+                "\n[pkg.{name}]\nversion = \"{rust_ver}\"\n\n\
+                 [pkg.{name}.target.\"{target}\"]\navailable = true\n\
+                 url = \"{url}/dist/{date}/{file_name}\"\nhash = \"{hash}\"\n",
+            )?;
+            let kind = if pkg.extension {
+                "extensions"
+            } else {
+                "components"
+            };
+            write!(
+                rust,
+                "\n[[pkg.rust.target.{BUILD_TARGET}.{kind}]]\n\
+                 pkg = \"{name}\"\ntarget = \"{target}\"\n",
+            )?;
+        }
+
+        let minimal = pkgs
+            .iter()
+            .filter(|it| !it.extension)
+            .map(|it| format!("\"{}\"", it.name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(
+            manifest,
+            "\n{rust}\n[profiles]\nminimal = [{minimal}]\ndefault = [{minimal}]\n\n\
+             [renames.clippy-preview]\nto = \"clippy\"\n\n\
+             [renames.rustfmt-preview]\nto = \"rustfmt\"\n",
+        )?;
+        Ok(Some(manifest))
+    }
+}
@@ -0,0 +1,1081 @@
+//! Offline tests of the transaction logic backed by [`FakeBackend`].
+
+use std::{collections::HashSet, env::consts::EXE_SUFFIX, fs, path::Path, thread, time::Duration};
+
+use gix_lock::acquire::Fail;
+
+use super::prelude::*;
+use crate::{
+    AddSubcmd, CompAddSubcmd, CompRmSubcmd, Config, DoctorSubcmd, EntryMetadata, Error, GcConfig,
+    GcSubcmd, ListSubcmd, PinSubcmd, Pool, Result, RmSubCmd, StatsSubcmd, UnpinSubcmd,
+    backend::FakeBackend,
+    entry::ENTRY_METADATA,
+    toolchain::{COMPONENTS_SUBPATH, IdentifiableToolchain},
+    util,
+};
+
+/// Long enough for concurrent transactions to overlap.
+const LATENCY: Duration = Duration::from_millis(300);
+
+#[test]
+fn toolchain_management() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+
+    let ver = "1.81.0";
+    let chan = "stable";
+
+    let report = add(&app_ctx, ver, None)?;
+
+    let tc_link = ctx.link(ver);
+    let underlying_path = resolve_link(&tc_link)?;
+    assert!(
+        underlying_path.exists(),
+        "underlying toolchain should exist"
+    );
+    let id = IdentifiableToolchain::new(&underlying_path)?.id();
+    assert_eq!(report.created, [id.as_str()]);
+
+    let report = add(&app_ctx, chan, Some(ver))?;
+    assert!(report.created.is_empty(), "underlying should be reused");
+    assert_eq!(resolve_link(&ctx.link(chan))?, underlying_path);
+
+    let toolchains = ListSubcmd {}.run(&app_ctx)?;
+    let ids = toolchains.iter().map(|tc| &tc.id).collect::<Vec<_>>();
+    assert_eq!(ids, [&id, &id]);
+
+    let report = RmSubCmd {
+        toolchain: chan.into(),
+    }
+    .run(&app_ctx)?;
+    assert!(report.removed.is_empty());
+    assert!(underlying_path.exists(), "underlying should still exist");
+
+    let report = RmSubCmd {
+        toolchain: ver.into(),
+    }
+    .run(&app_ctx)?;
+    assert_eq!(report.removed, [id]);
+    assert!(!tc_link.exists(), "original link should be gone");
+    assert!(!underlying_path.exists(), "underlying should be removed");
+
+    let err = RmSubCmd {
+        toolchain: ver.into(),
+    }
+    .run(&app_ctx)
+    .unwrap_err();
+    assert!(matches!(Error::find(&err), Some(Error::LinkNotFound(_))));
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn reject_invalid_names() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+    let outside = ctx.home().join("outside");
+    std::fs::create_dir(&outside)?;
+
+    let is_invalid = |res: Result<_>| {
+        matches!(
+            Error::find(&res.unwrap_err()),
+            Some(Error::InvalidToolchainName { .. })
+        )
+    };
+    for name in ["../../outside", "..", "stable/..", "C:foo", "NUL"] {
+        assert!(is_invalid(
+            RmSubCmd {
+                toolchain: name.into(),
+            }
+            .run(&app_ctx)
+        ));
+        assert!(is_invalid(add(&app_ctx, name, None)));
+        assert!(is_invalid(add(&app_ctx, "custom", Some(name))));
+        assert!(is_invalid(
+            CompAddSubcmd {
+                toolchain: name.into(),
+                components: vec!["clippy".into()],
+            }
+            .run(&app_ctx)
+        ));
+    }
+    assert!(
+        outside.exists(),
+        "nothing outside the home should be touched"
+    );
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn reject_non_host_targets() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+
+    // NOTE: This can never be the host target, as tests can't run on it.
+    let name = "stable-wasm32-unknown-unknown";
+    let is_invalid = |res: Result<_>| {
+        matches!(
+            Error::find(&res.unwrap_err()),
+            Some(Error::InvalidToolchainName { .. })
+        )
+    };
+    assert!(is_invalid(add(&app_ctx, name, None)));
+    assert!(is_invalid(add(&app_ctx, "custom", Some(name))));
+    let err = Pool::new(app_ctx.clone())
+        .identify_channel(name, &[])
+        .unwrap_err();
+    assert!(matches!(
+        Error::find(&err),
+        Some(Error::InvalidToolchainName { .. })
+    ));
+    assert!(pool_entries(&ctx)?.is_empty());
+
+    // The host target can still be spelled out.
+    add(&app_ctx, &format!("stable-{}", util::BUILD_TARGET), None)?;
+    assert_consistent(&app_ctx)?;
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn update_toolchain_gc() -> Result<()> {
+    let backend = FakeBackend::new();
+    backend.set_channel("stable", "1.91.0");
+    let ctx = Ctx::setup_fake(backend.clone())?;
+    let app_ctx = ctx.app_ctx();
+
+    let add_stable = || add(&app_ctx, "stable", None);
+
+    add_stable()?;
+    let underlying_v1 = resolve_link(&ctx.link("stable"))?;
+
+    backend.set_channel("stable", "1.92.0");
+    let report = add_stable()?;
+    let underlying_v2 = resolve_link(&ctx.link("stable"))?;
+
+    assert_ne!(underlying_v1, underlying_v2);
+    assert!(underlying_v2.exists(), "v2 toolchain should exist");
+    assert!(
+        !underlying_v1.exists(),
+        "v1 toolchain should have been GC'd"
+    );
+    assert_eq!(report.removed.len(), 1);
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn comp_add_rm() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+    let toolchain = "1.78";
+    let cargo_name = format!("cargo{EXE_SUFFIX}");
+
+    add(&app_ctx, toolchain, None)?;
+    let underlying_1 = resolve_link(&ctx.link(toolchain))?;
+    assert!(underlying_1.join("bin").join(&cargo_name).exists());
+
+    CompRmSubcmd {
+        toolchain: toolchain.into(),
+        components: vec!["cargo".into()],
+    }
+    .run(&app_ctx)?;
+    let underlying_2 = resolve_link(&ctx.link(toolchain))?;
+    assert_ne!(underlying_1, underlying_2);
+    assert!(!underlying_1.exists(), "old toolchain should be GC'd");
+    assert!(!underlying_2.join("bin").join(&cargo_name).exists());
+
+    CompAddSubcmd {
+        toolchain: toolchain.into(),
+        components: vec!["cargo".into()],
+    }
+    .run(&app_ctx)?;
+    let underlying_3 = resolve_link(&ctx.link(toolchain))?;
+    assert!(!underlying_2.exists(), "second toolchain should be GC'd");
+    assert_eq!(
+        underlying_1, underlying_3,
+        "should return to the original ID"
+    );
+    assert!(underlying_3.join("bin").join(&cargo_name).exists());
+
+    // Installing with the same components directly should land on the same ID.
+    AddSubcmd {
+        toolchain: "stable".into(),
+        source: Some(toolchain.into()),
+        components: vec!["clippy".into()],
+        allow_downgrade: false,
+    }
+    .run(&app_ctx)?;
+    CompAddSubcmd {
+        toolchain: toolchain.into(),
+        components: vec!["clippy".into()],
+    }
+    .run(&app_ctx)?;
+    assert_eq!(
+        resolve_link(&ctx.link("stable"))?,
+        resolve_link(&ctx.link(toolchain))?,
+    );
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn comp_renames() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+
+    AddSubcmd {
+        toolchain: "stable".into(),
+        source: None,
+        components: vec!["rustfmt-preview".into()],
+        allow_downgrade: false,
+    }
+    .run(&app_ctx)?;
+    add(&app_ctx, "1.81.0", None)?;
+    let original = resolve_link(&ctx.link("1.81.0"))?;
+
+    CompAddSubcmd {
+        toolchain: "1.81.0".into(),
+        components: vec!["rustfmt".into()],
+    }
+    .run(&app_ctx)?;
+    assert_eq!(
+        resolve_link(&ctx.link("1.81.0"))?,
+        resolve_link(&ctx.link("stable"))?,
+        "different spellings should share the same pool entry",
+    );
+
+    CompRmSubcmd {
+        toolchain: "1.81.0".into(),
+        components: vec!["rustfmt-preview".into()],
+    }
+    .run(&app_ctx)?;
+    assert_eq!(resolve_link(&ctx.link("1.81.0"))?, original);
+    assert_consistent(&app_ctx)?;
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn comp_add_unavailable() -> Result<()> {
+    let backend = FakeBackend::new();
+    backend.set_unavailable("nightly", "miri");
+    let ctx = Ctx::setup_fake(backend)?;
+    let app_ctx = ctx.app_ctx();
+
+    add(&app_ctx, "nightly", None)?;
+    let underlying = resolve_link(&ctx.link("nightly"))?;
+
+    let err = CompAddSubcmd {
+        toolchain: "nightly".into(),
+        components: vec!["clippy".into(), "miri".into()],
+    }
+    .run(&app_ctx)
+    .unwrap_err();
+    assert!(matches!(
+        Error::find(&err),
+        Some(Error::ComponentsUnavailable { components, .. })
+            if components.len() == 1 && components[0].starts_with("miri-"),
+    ));
+    assert_eq!(resolve_link(&ctx.link("nightly"))?, underlying);
+    assert_eq!(
+        app_ctx.rustup_home.join("toolchains").read_dir()?.count(),
+        1,
+        "the toolchain should not have been cloned",
+    );
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn comp_add_links_base() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+    let cargo_name = format!("cargo{EXE_SUFFIX}");
+
+    for toolchain in ["stable", "1.81.0"] {
+        add(&app_ctx, toolchain, Some("stable"))?;
+    }
+    let base = resolve_link(&ctx.link("stable"))?;
+    let components = fs::read_to_string(base.join(*COMPONENTS_SUBPATH))?;
+    let metadata = fs::read_to_string(base.join(ENTRY_METADATA))?;
+
+    CompAddSubcmd {
+        toolchain: "stable".into(),
+        components: vec!["clippy".into()],
+    }
+    .run(&app_ctx)?;
+    let modified = resolve_link(&ctx.link("stable"))?;
+    assert_ne!(modified, base);
+    assert_eq!(resolve_link(&ctx.link("1.81.0"))?, base);
+
+    // The files rewritten in place must have been copied...
+    assert_eq!(
+        fs::read_to_string(base.join(*COMPONENTS_SUBPATH))?,
+        components
+    );
+    assert_eq!(fs::read_to_string(base.join(ENTRY_METADATA))?, metadata);
+    // ... whereas the rest should be shared with the base entry.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let ino = |dir: &std::path::Path| {
+            fs::metadata(dir.join("bin").join(&cargo_name)).map(|it| it.ino())
+        };
+        assert_eq!(ino(&base)?, ino(&modified)?);
+    }
+    assert!(modified.join("bin").join(&cargo_name).exists());
+    assert_consistent(&app_ctx)?;
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn add_allow_downgrade() -> Result<()> {
+    let backend = FakeBackend::new();
+    backend.set_unavailable("nightly", "clippy");
+    backend.set_channel("nightly-2024-09-04", "1.83.0-nightly");
+    backend.set_unavailable("nightly-2024-09-04", "clippy");
+    // NOTE: `nightly-2024-09-03` has not been archived.
+    backend.set_channel("nightly-2024-09-02", "1.82.0-nightly");
+    let ctx = Ctx::setup_fake(backend.clone())?;
+    let app_ctx = ctx.app_ctx();
+
+    let add_nightly = |allow_downgrade| {
+        AddSubcmd {
+            toolchain: "nightly".into(),
+            source: None,
+            components: vec!["clippy".into()],
+            allow_downgrade,
+        }
+        .run(&app_ctx)
+    };
+
+    let err = add_nightly(false).unwrap_err();
+    assert!(matches!(
+        Error::find(&err),
+        Some(Error::ComponentsUnavailable { .. }),
+    ));
+    assert!(!ctx.link("nightly").exists());
+
+    let report = add_nightly(true)?;
+    assert_eq!(report.version.as_deref(), Some("1.82.0-nightly (fake)"));
+    let underlying = resolve_link(&ctx.link("nightly"))?;
+    assert!(
+        underlying
+            .join("bin")
+            .join(format!("clippy{EXE_SUFFIX}"))
+            .exists()
+    );
+
+    // Only the days that have not been archived are skipped, while other
+    // failures are not taken for missing components.
+    backend.set_unreachable("nightly-2024-09-04");
+    let err = add_nightly(true).unwrap_err();
+    assert!(Error::find(&err).is_none(), "{err:?}");
+    assert!(format!("{err:#}").contains("500 Internal Server Error"));
+    assert_eq!(resolve_link(&ctx.link("nightly"))?, underlying);
+    assert_consistent(&app_ctx)?;
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn entry_metadata() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+
+    add(&app_ctx, "stable", None)?;
+    CompAddSubcmd {
+        toolchain: "stable".into(),
+        components: vec!["clippy".into()],
+    }
+    .run(&app_ctx)?;
+
+    let underlying = resolve_link(&ctx.link("stable"))?;
+    let metadata = EntryMetadata::load(&underlying)?.expect("metadata should exist");
+    assert_eq!(metadata.id_scheme, 1);
+    assert_eq!(
+        metadata.source,
+        Some(util::qualify_with_target("stable").into())
+    );
+    assert_eq!(metadata.rust_ver, "1.81.0 (fake)");
+    assert!(
+        metadata
+            .components
+            .contains(&*util::qualify_with_target("clippy"))
+    );
+
+    let toolchains = ListSubcmd {}.run(&app_ctx)?;
+    assert_eq!(toolchains[0].version.as_deref(), Some("1.81.0 (fake)"));
+
+    // An unreferenced entry without metadata is only collected after `doctor`
+    // has looked into it.
+    add(&app_ctx, "1.80.0", None)?;
+    let legacy = resolve_link(&ctx.link("1.80.0"))?;
+    fs::remove_file(legacy.join(ENTRY_METADATA))?;
+    util::soft_unlink(&ctx.link("1.80.0"))?;
+
+    assert!(
+        GcSubcmd { unused_days: None }
+            .run(&app_ctx)?
+            .removed
+            .is_empty()
+    );
+    assert!(legacy.exists());
+    let report = DoctorSubcmd {}.run(&app_ctx)?;
+    assert_eq!(report.removed.len(), 1);
+    assert!(!legacy.exists());
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn verify_on_reuse() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+    let add = |toolchain: &str, source: &str| add(&app_ctx, toolchain, Some(source));
+
+    add("stable", "1.81.0")?;
+    let entry = resolve_link(&ctx.link("stable"))?;
+    let id = entry.file_name().unwrap().to_string_lossy().into_owned();
+
+    // A broken entry is installed again.
+    fs::remove_file(entry.join("lib/rustlib/components"))?;
+    let report = add("custom", "1.81.0")?;
+    assert_eq!(report.created, [&*id]);
+    assert_eq!(resolve_link(&ctx.link("custom"))?, entry);
+    assert_consistent(&app_ctx)?;
+
+    // An entry with the wrong contents is refused.
+    add("old", "1.80.0")?;
+    let old_entry = resolve_link(&ctx.link("old"))?;
+    fs::remove_dir_all(&entry)?;
+    util::copy_dir_all(&old_entry, &entry)?;
+    let err = add("another", "1.81.0").unwrap_err();
+    assert!(matches!(
+        Error::find(&err),
+        Some(Error::EntryMismatch { id: it, .. }) if *it == id,
+    ));
+    assert!(!ctx.link("another").exists());
+
+    // ... until `doctor` sorts it out.
+    DoctorSubcmd {}.run(&app_ctx)?;
+    assert_consistent(&app_ctx)?;
+    add("another", "1.81.0")?;
+    assert_consistent(&app_ctx)?;
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn migrate_id_scheme() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+
+    for toolchain in ["stable", "1.81.0", "1.80.0"] {
+        add(&app_ctx, toolchain, None)?;
+    }
+    let current = resolve_link(&ctx.link("stable"))?;
+
+    // Pretend that `stable` and `1.81.0` were installed under a legacy ID.
+    let legacy = current.with_file_name("1.81.0-legacy-id");
+    assert_eq!(IdentifiableToolchain::id_scheme("1.81.0-legacy-id"), 1);
+    fs::rename(&current, &legacy)?;
+    for toolchain in ["stable", "1.81.0"] {
+        util::soft_unlink(&ctx.link(toolchain))?;
+        util::soft_link(&legacy, &ctx.link(toolchain))?;
+    }
+
+    DoctorSubcmd {}.run(&app_ctx)?;
+    assert!(
+        !legacy.exists(),
+        "the legacy entry should have been renamed"
+    );
+    for toolchain in ["stable", "1.81.0"] {
+        assert_eq!(resolve_link(&ctx.link(toolchain))?, current);
+    }
+    assert_consistent(&app_ctx)?;
+
+    // Opting into strict IDs migrates every entry to the newest scheme, and
+    // opting out migrates them back.
+    let scheme = |path: &Path| IdentifiableToolchain::id_scheme(&path.to_string_lossy());
+    for (strict_ids, expected) in [(true, IdentifiableToolchain::ID_SCHEME), (false, 1)] {
+        Config {
+            strict_ids,
+            ..Config::default()
+        }
+        .write(&app_ctx.rynzland_home)?;
+        DoctorSubcmd {}.run(&app_ctx)?;
+        for toolchain in ["stable", "1.81.0", "1.80.0"] {
+            let entry = resolve_link(&ctx.link(toolchain))?;
+            assert_eq!(scheme(entry.file_name().unwrap().as_ref()), expected);
+            let metadata = EntryMetadata::load(&entry)?.expect("metadata should exist");
+            assert_eq!(metadata.id_scheme, expected);
+        }
+        assert_eq!(pool_entries(&ctx)?.len(), 2);
+        assert_consistent(&app_ctx)?;
+    }
+    assert_eq!(resolve_link(&ctx.link("stable"))?, current);
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn reuse_other_id_scheme() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+    let strict = |strict_ids| {
+        Config {
+            strict_ids,
+            ..Config::default()
+        }
+        .write(&app_ctx.rynzland_home)
+    };
+
+    strict(true)?;
+    let report = add(&app_ctx, "stable", None)?;
+    assert_eq!(report.created.len(), 1);
+    let entry = resolve_link(&ctx.link("stable"))?;
+    let id = entry.file_name().unwrap().to_string_lossy().into_owned();
+    assert_eq!(
+        IdentifiableToolchain::id_scheme(&id),
+        IdentifiableToolchain::ID_SCHEME
+    );
+
+    // Switching the scheme must neither reinstall the entry nor duplicate it.
+    strict(false)?;
+    let report = add(&app_ctx, "1.81.0", None)?;
+    assert!(report.created.is_empty(), "{report:?}");
+    assert_eq!(report.links[0].to.as_deref(), Some(&*id));
+    assert_eq!(resolve_link(&ctx.link("1.81.0"))?, entry);
+
+    let report = CompAddSubcmd {
+        toolchain: "stable".into(),
+        components: vec!["clippy".into()],
+    }
+    .run(&app_ctx)?;
+    assert_eq!(report.created.len(), 1);
+    let with_clippy = resolve_link(&ctx.link("stable"))?;
+    assert_eq!(
+        IdentifiableToolchain::id_scheme(&with_clippy.file_name().unwrap().to_string_lossy()),
+        1
+    );
+
+    strict(true)?;
+    let report = CompAddSubcmd {
+        toolchain: "1.81.0".into(),
+        components: vec!["clippy".into()],
+    }
+    .run(&app_ctx)?;
+    assert!(report.created.is_empty(), "{report:?}");
+    assert_eq!(resolve_link(&ctx.link("1.81.0"))?, with_clippy);
+    assert_eq!(pool_entries(&ctx)?.len(), 1);
+    assert_consistent(&app_ctx)?;
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn concurrent_add_same() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new().with_latency(LATENCY))?;
+    let toolchain = "1.80.0";
+
+    let mut handles = Vec::new();
+    for _ in 0..5 {
+        let app_ctx = ctx.app_ctx();
+        handles.push(thread::spawn(move || add(&app_ctx, toolchain, None)));
+    }
+
+    let (successes, failures): (Vec<_>, Vec<_>) = handles
+        .into_iter()
+        .map(|it| it.join().expect("thread panicked"))
+        .partition(Result::is_ok);
+    assert_eq!(successes.len(), 1, "only one thread should succeed");
+    for err in failures.into_iter().filter_map(Result::err) {
+        assert!(
+            matches!(Error::find(&err), Some(Error::ToolchainBusy(_))),
+            "unexpected error: {err:?}",
+        );
+    }
+    assert!(resolve_link(&ctx.link(toolchain))?.exists());
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn concurrent_add_same_id() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new().with_latency(LATENCY))?;
+    let ver = "1.80.0";
+
+    let handles = ["stable", "1.80"].map(|toolchain| {
+        let app_ctx = ctx.app_ctx();
+        thread::spawn(move || add(&app_ctx, toolchain, Some(ver)))
+    });
+    let (successes, failures): (Vec<_>, Vec<_>) = handles
+        .into_iter()
+        .map(|it| it.join().expect("thread panicked"))
+        .partition(Result::is_ok);
+    assert_eq!(successes.len(), 1, "only one thread should succeed");
+    let err = failures.into_iter().next().unwrap().unwrap_err();
+    let winner = ["stable", "1.80"].map(|it| ctx.link(it));
+    let winner = winner.iter().find(|it| it.exists()).unwrap();
+    let id = IdentifiableToolchain::new(&resolve_link(winner)?)?.id();
+    assert!(
+        matches!(Error::find(&err), Some(Error::EntryBusy(it)) if *it == id),
+        "unexpected error: {err:?}",
+    );
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn concurrent_comp_rm_same_target() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new().with_latency(LATENCY))?;
+    let ver = "1.80.0";
+    let toolchains = ["stable", "1.80", ver];
+
+    for toolchain in toolchains {
+        add(&ctx.app_ctx(), toolchain, Some(ver))?;
+    }
+    let underlying = toolchains
+        .iter()
+        .map(|tc| resolve_link(&ctx.link(tc)))
+        .collect::<Result<HashSet<_>>>()?;
+    assert_eq!(underlying.len(), 1, "all toolchains should share the pool");
+
+    let handles = toolchains.map(|toolchain| {
+        let app_ctx = ctx.app_ctx();
+        thread::spawn(move || {
+            CompRmSubcmd {
+                toolchain: toolchain.into(),
+                components: vec!["cargo".into()],
+            }
+            .run(&app_ctx)
+        })
+    });
+    let successes = handles
+        .into_iter()
+        .filter_map(|it| it.join().expect("thread panicked").ok())
+        .count();
+    assert_eq!(successes, 1, "only one thread should succeed");
+
+    let pool_entries = ctx
+        .home()
+        .join("rustup_home")
+        .join("toolchains")
+        .read_dir()?
+        .filter(|it| it.as_ref().is_ok_and(|it| it.path().is_dir()))
+        .count();
+    assert_eq!(
+        pool_entries, 2,
+        "the pool should contain the original entry and the one without cargo",
+    );
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn gc_unreferenced() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+
+    add(&app_ctx, "stable", None)?;
+    let underlying = resolve_link(&ctx.link("stable"))?;
+
+    // Simulate a crash between the installation and the link creation.
+    let orphan = underlying.with_file_name("1.0.0-orphan");
+    crate::util::copy_dir_all(&underlying, &orphan)?;
+
+    let report = GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    assert_eq!(report.removed, ["1.0.0-orphan"]);
+    assert!(!orphan.exists(), "orphan should be GC'd");
+    assert!(underlying.exists(), "referenced entry should be kept");
+
+    drop(ctx);
+    Ok(())
+}
+
+/// Returns the IDs of the entries in the pool.
+fn pool_entries(ctx: &Ctx) -> Result<HashSet<String>> {
+    let mut ids = HashSet::new();
+    for entry in ctx
+        .home()
+        .join("rustup_home")
+        .join("toolchains")
+        .read_dir()?
+    {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            ids.insert(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    Ok(ids)
+}
+
+#[test]
+fn gc_pins() -> Result<()> {
+    let backend = FakeBackend::new();
+    backend.set_channel("stable", "1.80.0");
+    let ctx = Ctx::setup_fake(backend.clone())?;
+    let app_ctx = ctx.app_ctx();
+    let add = || add(&app_ctx, "stable", None);
+
+    add()?;
+    let pinned = pool_entries(&ctx)?.into_iter().next().unwrap();
+    assert!(
+        PinSubcmd {
+            id: "nonexistent".into()
+        }
+        .run(&app_ctx)
+        .is_err()
+    );
+    let change = PinSubcmd { id: pinned.clone() }.run(&app_ctx)?;
+    assert_eq!(change.pinned, [&*pinned]);
+    let change = PinSubcmd { id: pinned.clone() }.run(&app_ctx)?;
+    assert!(change.pinned.is_empty(), "the entry is already pinned");
+
+    backend.set_channel("stable", "1.81.0");
+    let report = add()?;
+    assert!(report.removed.is_empty(), "the pinned entry should be kept");
+    GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    assert!(pool_entries(&ctx)?.contains(&pinned));
+
+    let change = UnpinSubcmd { id: pinned.clone() }.run(&app_ctx)?;
+    assert_eq!(change.unpinned, [&*pinned]);
+    assert!(change.pinned.is_empty());
+    let report = GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    assert_eq!(report.removed, [pinned]);
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn concurrent_pins() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx
+        .app_ctx()
+        .with_gc_lock_backoff(Fail::AfterDurationWithBackoff(Duration::from_mins(1)));
+    for (toolchain, ver) in [("a", "1.78.0"), ("b", "1.79.0"), ("c", "1.80.0")] {
+        add(&app_ctx, toolchain, Some(ver))?;
+    }
+
+    let ids = pool_entries(&ctx)?;
+    thread::scope(|s| {
+        for id in &ids {
+            let app_ctx = &app_ctx;
+            s.spawn(move || PinSubcmd { id: id.clone() }.run(app_ctx).unwrap());
+        }
+    });
+    let pins = Config::load(&app_ctx.rynzland_home)?.gc.pins;
+    assert_eq!(
+        pins.into_iter().collect::<HashSet<_>>(),
+        ids,
+        "no pin should be lost"
+    );
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn gc_grace_period() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+    Config {
+        gc: GcConfig {
+            grace_period: 3600,
+            ..GcConfig::default()
+        },
+        ..Config::default()
+    }
+    .write(&app_ctx.rynzland_home)?;
+    let add = || add(&app_ctx, "stable", None);
+    let rm = || {
+        RmSubCmd {
+            toolchain: "stable".into(),
+        }
+        .run(&app_ctx)
+    };
+
+    add()?;
+    let underlying = resolve_link(&ctx.link("stable"))?;
+    let report = rm()?;
+    assert!(report.removed.is_empty(), "the entry should be kept");
+    let released_at =
+        |path| Ok::<_, anyhow::Error>(EntryMetadata::load(path)?.unwrap().released_at);
+    assert!(released_at(&underlying)?.is_some());
+
+    // Relinking the entry within the grace period ends the latter.
+    let report = add()?;
+    assert!(report.created.is_empty(), "the entry should be reused");
+    GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    assert_eq!(released_at(&underlying)?, None);
+
+    rm()?;
+    let metadata_path = underlying.join(ENTRY_METADATA);
+    let written = fs::read_to_string(&metadata_path)?;
+    thread::sleep(Duration::from_secs(1));
+    GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    assert!(underlying.exists());
+    assert_eq!(
+        fs::read_to_string(&metadata_path)?,
+        written,
+        "the grace period should not be restarted",
+    );
+
+    let mut metadata = EntryMetadata::load(&underlying)?.unwrap();
+    metadata.released_at = Some(0);
+    metadata.write(&underlying)?;
+    let report = GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    assert_eq!(report.removed.len(), 1);
+    assert!(!underlying.exists(), "the grace period should be over");
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn gc_keep_last() -> Result<()> {
+    let backend = FakeBackend::new();
+    let ctx = Ctx::setup_fake(backend.clone())?;
+    let app_ctx = ctx.app_ctx();
+    Config {
+        gc: GcConfig {
+            keep_last: 2,
+            ..GcConfig::default()
+        },
+        ..Config::default()
+    }
+    .write(&app_ctx.rynzland_home)?;
+
+    let mut ids = vec![];
+    for ver in ["1.79.0", "1.80.0", "1.81.0"] {
+        backend.set_channel("stable", ver);
+        add(&app_ctx, "stable", None)?;
+        let underlying = resolve_link(&ctx.link("stable"))?;
+        ids.push(
+            underlying
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+        );
+    }
+    GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    assert_eq!(
+        pool_entries(&ctx)?,
+        HashSet::from([ids[1].clone(), ids[2].clone()]),
+        "only the last 2 versions of `stable` should be kept",
+    );
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn quota_evicts_lru() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+    let mut config = Config {
+        gc: GcConfig {
+            grace_period: 3600,
+            ..GcConfig::default()
+        },
+        ..Config::default()
+    };
+    config.write(&app_ctx.rynzland_home)?;
+
+    let mut entries = vec![];
+    for (toolchain, ver) in [("a", "1.79.0"), ("b", "1.80.0")] {
+        add(&app_ctx, toolchain, Some(ver))?;
+        entries.push(resolve_link(&ctx.link(toolchain))?);
+        RmSubCmd {
+            toolchain: toolchain.into(),
+        }
+        .run(&app_ctx)?;
+    }
+    // `b` has been installed later, but `a` has been used since.
+    let long_ago = std::time::SystemTime::UNIX_EPOCH;
+    let touch = |entry: &std::path::Path, time| -> Result<()> {
+        crate::entry::touch(entry)?;
+        fs::File::options()
+            .write(true)
+            .open(entry.join(crate::entry::LAST_USED))?
+            .set_modified(time)?;
+        Ok(())
+    };
+    let mut metadata = EntryMetadata::load(&entries[1])?.unwrap();
+    metadata.installed_at = 0;
+    metadata.write(&entries[1])?;
+    touch(&entries[1], long_ago)?;
+    crate::entry::touch(&entries[0])?;
+
+    let size = app_ctx.pool_size()?;
+    config.gc.max_pool_size = Some(size);
+    config.write(&app_ctx.rynzland_home)?;
+    let report = add(&app_ctx, "c", Some("1.81.0"))?;
+    let b = entries[1].file_name().unwrap().to_string_lossy();
+    assert_eq!(
+        report.removed,
+        [b],
+        "the least recently used entry should go"
+    );
+    assert!(entries[0].exists());
+    assert!(app_ctx.pool_size()? <= size);
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn quota_evicts_referenced() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+    Config {
+        gc: GcConfig {
+            max_pool_size: Some(1),
+            ..GcConfig::default()
+        },
+        ..Config::default()
+    }
+    .write(&app_ctx.rynzland_home)?;
+    let add = |toolchain: &str, ver: &str| add(&app_ctx, toolchain, Some(ver));
+
+    // Referenced entries are kept unless told otherwise.
+    add("a", "1.79.0")?;
+    let report = add("b", "1.80.0")?;
+    assert!(report.removed.is_empty());
+    assert_eq!(pool_entries(&ctx)?.len(), 2);
+
+    Config {
+        gc: GcConfig {
+            max_pool_size: Some(1),
+            evict_referenced: true,
+            ..GcConfig::default()
+        },
+        ..Config::default()
+    }
+    .write(&app_ctx.rynzland_home)?;
+    let report = add("c", "1.81.0")?;
+    assert_eq!(report.removed.len(), 2);
+    assert!(report.links.iter().any(|it| it.to.is_none()));
+    assert!(ctx.link("a").symlink_metadata().is_err());
+    assert!(ctx.link("b").symlink_metadata().is_err());
+    assert_eq!(
+        pool_entries(&ctx)?,
+        HashSet::from([resolve_link(&ctx.link("c"))?
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()]),
+        "the newly installed entry should be kept",
+    );
+    assert_consistent(&app_ctx)?;
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn gc_unused_days() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+
+    for (toolchain, ver) in [("a", "1.79.0"), ("b", "1.80.0")] {
+        add(&app_ctx, toolchain, Some(ver))?;
+    }
+    let pool = Pool::new(app_ctx.clone());
+    pool.record_use("a")?;
+    pool.record_use("a")?;
+    let usage = StatsSubcmd {}.run(&app_ctx)?;
+    assert_eq!(
+        usage.iter().map(|it| it.count).collect::<Vec<_>>(),
+        [2, 0],
+        "{usage:?}",
+    );
+    assert!(usage[1].last_used.is_none());
+
+    // Toolchains are not considered unused before they have been linked for that
+    // long, even if never run.
+    let days_ago = |days: i64| {
+        let now = filetime::FileTime::now();
+        filetime::FileTime::from_unix_time(now.unix_seconds() - days * 24 * 60 * 60, 0)
+    };
+    let b = ctx.link("b");
+    let entry_b = resolve_link(&b)?;
+    filetime::set_symlink_file_times(&b, days_ago(10), days_ago(10))?;
+    for unused_days in [30, u64::MAX] {
+        let report = GcSubcmd {
+            unused_days: Some(unused_days),
+        }
+        .run(&app_ctx)?;
+        assert!(report.links.is_empty(), "{report:?}");
+    }
+
+    // Pretend that `b` has been linked long ago.
+    filetime::set_symlink_file_times(&b, days_ago(60), days_ago(60))?;
+    let report = GcSubcmd {
+        unused_days: Some(30),
+    }
+    .run(&app_ctx)?;
+    assert_eq!(report.links.len(), 1, "{report:?}");
+    assert!(!b.exists() && ctx.link("a").exists());
+    assert!(!entry_b.exists());
+    let usage = StatsSubcmd {}.run(&app_ctx)?;
+    assert_eq!(usage.len(), 1);
+    assert_consistent(&app_ctx)?;
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+fn gc_download_cache_broken_entry() -> Result<()> {
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let app_ctx = ctx.app_ctx();
+
+    add(&app_ctx, "stable", None)?;
+    let entry = resolve_link(&ctx.link("stable"))?;
+    fs::remove_file(entry.join(*COMPONENTS_SUBPATH))?;
+
+    let cache = app_ctx.rynzland_home.join("cache");
+    fs::create_dir_all(&cache)?;
+    let pkg = cache.join("0".repeat(64));
+    fs::write(&pkg, "")?;
+    let long_ago = filetime::FileTime::zero();
+    filetime::set_file_mtime(&pkg, long_ago)?;
+
+    // The broken entry must neither fail the GC nor let its packages be swept.
+    GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    DoctorSubcmd {}.run(&app_ctx)?;
+    assert!(pkg.exists());
+
+    RmSubCmd {
+        toolchain: "stable".into(),
+    }
+    .run(&app_ctx)?;
+    GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    assert!(!pkg.exists());
+
+    drop(ctx);
+    Ok(())
+}
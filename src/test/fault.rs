@@ -0,0 +1,190 @@
+//! Crash tests of the transactions, each crashing a worker process at a fault
+//! point and checking that the home can be recovered from the state left
+//! behind.
+
+use std::env::{self, consts::EXE_SUFFIX};
+
+use super::prelude::*;
+use crate::{
+    CompAddSubcmd, Config, Ctx as AppCtx, DoctorSubcmd, GcConfig, Report, Result, RmSubCmd,
+    backend::FakeBackend,
+    fault::{FAULT_EXIT_CODE, FAULT_POINT_ENV},
+    util,
+};
+
+/// The home directory of the worker, whose presence also tells a worker apart
+/// from a regular run of [`worker`].
+const HOME_ENV: &str = "RYNZLAND_FAULT_HOME";
+
+/// The transaction to be run by the worker.
+const OP_ENV: &str = "RYNZLAND_FAULT_OP";
+
+#[test]
+#[ignore = "only meaningful as a child process of the crash tests"]
+fn worker() -> Result<()> {
+    let Some(home) = env::var_os(HOME_ENV) else {
+        return Ok(());
+    };
+    let ctx = AppCtx::new(home).with_backend(FakeBackend::new());
+    run_op(&ctx, &env::var(OP_ENV)?)?;
+    Ok(())
+}
+
+fn run_op(ctx: &AppCtx, op: &str) -> Result<Report> {
+    match op {
+        "add" => add(ctx, "stable", None),
+        "comp-add" => CompAddSubcmd {
+            toolchain: "stable".into(),
+            components: vec!["clippy".into()],
+        }
+        .run(ctx),
+        "rm" => RmSubCmd {
+            toolchain: "stable".into(),
+        }
+        .run(ctx),
+        _ => unreachable!("unknown op `{op}`"),
+    }
+}
+
+/// Crashes `op` at `point` in a worker process, then recovers the home.
+fn crash_and_recover(ctx: &Ctx, op: &str, point: &str) -> Result<()> {
+    crash(ctx, op, point)?;
+    recover(ctx)
+}
+
+/// Crashes `op` at `point` in a worker process.
+fn crash(ctx: &Ctx, op: &str, point: &str) -> Result<()> {
+    let output = respawn("test::fault::worker")?
+        .env(HOME_ENV, ctx.home())
+        .env(OP_ENV, op)
+        .env(FAULT_POINT_ENV, point)
+        .output()?;
+    assert_eq!(
+        output.status.code(),
+        Some(FAULT_EXIT_CODE),
+        "`{op}` should have crashed at `{point}`: {}",
+        String::from_utf8_lossy(&output.stderr),
+    );
+    Ok(())
+}
+
+/// Recovers the home from a crash, checking that nothing is left behind.
+fn recover(ctx: &Ctx) -> Result<()> {
+    let app_ctx = ctx.app_ctx();
+    DoctorSubcmd {}.run(&app_ctx)?;
+    assert_consistent(&app_ctx)?;
+    assert_all_referenced(&app_ctx)
+}
+
+/// Sets up a home where `stable` is linked to 1.80.0, while the workers will
+/// see it at 1.81.0.
+fn setup_outdated_stable() -> Result<Ctx> {
+    let backend = FakeBackend::new();
+    backend.set_channel("stable", "1.80.0");
+    let ctx = Ctx::setup_fake(backend.clone())?;
+    run_op(&ctx.app_ctx(), "add")?;
+    backend.set_channel("stable", "1.81.0");
+    Ok(ctx)
+}
+
+#[test]
+fn crash_add() -> Result<()> {
+    for point in [
+        "add:in-flight",
+        "add:installed",
+        "add:relinked",
+        "gc:removing",
+        "gc:trashed",
+    ] {
+        let ctx = setup_outdated_stable()?;
+        crash_and_recover(&ctx, "add", point)?;
+
+        let app_ctx = ctx.app_ctx();
+        let report = run_op(&app_ctx, "add")?;
+        assert_eq!(report.version.as_deref(), Some("1.81.0 (fake)"));
+        assert_consistent(&app_ctx)?;
+        assert_all_referenced(&app_ctx)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_comp_add() -> Result<()> {
+    for point in [
+        "comp:in-flight",
+        "comp:cloned",
+        "comp:installed",
+        "comp:relinked",
+        "gc:removing",
+        "gc:trashed",
+    ] {
+        let ctx = Ctx::setup_fake(FakeBackend::new())?;
+        run_op(&ctx.app_ctx(), "add")?;
+        crash_and_recover(&ctx, "comp-add", point)?;
+
+        let app_ctx = ctx.app_ctx();
+        run_op(&app_ctx, "comp-add")?;
+        let underlying = resolve_link(&ctx.link("stable"))?;
+        let clippy = underlying.join("bin").join(format!("clippy{EXE_SUFFIX}"));
+        assert!(clippy.exists(), "clippy should have been added");
+        assert_consistent(&app_ctx)?;
+        assert_all_referenced(&app_ctx)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_rm() -> Result<()> {
+    for point in ["gc:removing", "gc:trashed"] {
+        let ctx = Ctx::setup_fake(FakeBackend::new())?;
+        run_op(&ctx.app_ctx(), "add")?;
+        crash_and_recover(&ctx, "rm", point)?;
+
+        assert!(ctx.link("stable").symlink_metadata().is_err());
+        let rustup_home = ctx.home().join("rustup_home");
+        let pool_entries = rustup_home
+            .join("toolchains")
+            .read_dir()?
+            .filter(|it| it.as_ref().is_ok_and(|it| it.path().is_dir()))
+            .count();
+        assert_eq!(pool_entries, 0, "the pool should be empty");
+        assert_eq!(
+            rustup_home.join("trash").read_dir()?.count(),
+            0,
+            "the trash should have been swept",
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_quota_eviction() -> Result<()> {
+    for point in ["gc:removing", "gc:trashed"] {
+        let ctx = Ctx::setup_fake(FakeBackend::new())?;
+        let app_ctx = ctx.app_ctx();
+        add(&app_ctx, "old", Some("1.80.0"))?;
+        Config {
+            gc: GcConfig {
+                max_pool_size: Some(1),
+                evict_referenced: true,
+                ..GcConfig::default()
+            },
+            ..Config::default()
+        }
+        .write(&app_ctx.rynzland_home)?;
+        crash(&ctx, "add", point)?;
+
+        // The evicted link must stay claimed until its entry is gone, or it could
+        // be added back to the entry being removed.
+        assert!(ctx.link("old").symlink_metadata().is_err());
+        let claim = util::with_tmp(&ctx.link("old"));
+        assert!(
+            claim.symlink_metadata().is_ok(),
+            "the evicted link should still be claimed at `{point}`",
+        );
+
+        recover(&ctx)?;
+        assert!(claim.symlink_metadata().is_err());
+    }
+    Ok(())
+}
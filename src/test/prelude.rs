@@ -1,14 +1,21 @@
 use std::{
-    fs,
+    env, fs,
     path::{Path, PathBuf},
+    process::{Command, Stdio},
 };
 
 use anyhow::Result;
 
-use crate::{Ctx as AppCtx, SetupSubcmd};
+pub use super::dist::DistServer;
+use crate::{
+    AddSubcmd, Ctx as AppCtx, Report, SetupSubcmd, backend::FakeBackend,
+    toolchain::IdentifiableToolchain, util,
+};
 
 pub struct Ctx {
     tempdir: tempfile::TempDir,
+    dist: Option<DistServer>,
+    fake: Option<FakeBackend>,
 }
 
 impl Ctx {
@@ -18,16 +25,36 @@ impl Ctx {
         let tempdir_path = tempdir.path();
         fs::create_dir_all(tempdir_path.join("home"))?;
 
-        Ok(Self { tempdir })
+        Ok(Self {
+            tempdir,
+            dist: None,
+            fake: None,
+        })
     }
 
-    /// Like [`Self::new`], but also runs setup.
+    /// Like [`Self::new`], but also runs setup against a fresh
+    /// [`DistServer`], so that no network access is required.
     pub fn setup() -> Result<Self> {
-        let ctx = Self::new()?;
+        let mut ctx = Self::new()?;
+        ctx.dist = Some(DistServer::start()?);
         SetupSubcmd {}.run(&ctx.app_ctx())?;
         Ok(ctx)
     }
 
+    /// Like [`Self::setup`], but with the given [`FakeBackend`] so that no
+    /// network access is required.
+    pub fn setup_fake(backend: FakeBackend) -> Result<Self> {
+        let mut ctx = Self::new()?;
+        ctx.fake = Some(backend);
+        SetupSubcmd {}.run(&ctx.app_ctx())?;
+        Ok(ctx)
+    }
+
+    /// Returns the [`DistServer`] started by [`Self::setup`].
+    pub fn dist(&self) -> &DistServer {
+        self.dist.as_ref().expect("no dist server is running")
+    }
+
     pub fn dir(&self) -> &Path {
         self.tempdir.path()
     }
@@ -37,6 +64,98 @@ impl Ctx {
     }
 
     pub fn app_ctx(&self) -> AppCtx {
-        AppCtx::new(self.home())
+        let mut ctx = AppCtx::new(self.home());
+        if let Some(dist) = &self.dist {
+            ctx = ctx.with_dist_server(dist.url());
+        }
+        match &self.fake {
+            Some(backend) => ctx.with_backend(backend.clone()),
+            None => ctx,
+        }
+    }
+
+    /// Returns the path of the link to `toolchain`.
+    pub fn link(&self, toolchain: &str) -> PathBuf {
+        self.home()
+            .join("rynzland_home")
+            .join("toolchains")
+            .join(util::qualify_with_target(toolchain).as_ref())
+    }
+}
+
+/// Adds the link `toolchain` installed from `source` with no extra components.
+pub fn add(ctx: &AppCtx, toolchain: &str, source: Option<&str>) -> Result<Report> {
+    AddSubcmd {
+        toolchain: toolchain.into(),
+        source: source.map(Into::into),
+        components: vec![],
+        allow_downgrade: false,
+    }
+    .run(ctx)
+}
+
+/// Returns the path of the pool entry referenced by the link at `path`.
+pub fn resolve_link(path: &Path) -> Result<PathBuf> {
+    let link_target = util::soft_link_target(path)?;
+    if link_target.is_relative() {
+        Ok(path.with_file_name(link_target))
+    } else {
+        Ok(link_target)
+    }
+}
+
+/// Returns a command re-executing the test binary to only run the ignored
+/// test `name`, which is expected to act as a worker process.
+pub fn respawn(name: &str) -> Result<Command> {
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.args(["--exact", name, "--ignored", "--nocapture"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    Ok(cmd)
+}
+
+/// Asserts that no transaction is left in flight and that every link refers
+/// to a pool entry whose ID matches its contents.
+pub fn assert_consistent(ctx: &AppCtx) -> Result<()> {
+    for entry in ctx.rynzland_home.join("toolchains").read_dir()? {
+        let path = entry?.path();
+        assert!(!util::is_tmp(&path), "in-flight link left: {path:?}");
+        let underlying = resolve_link(&path)?;
+        assert!(underlying.is_dir(), "dangling link: {path:?}");
+    }
+
+    for entry in ctx.rustup_home.join("toolchains").read_dir()? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        assert!(!util::is_tmp(&path), "temporary entry left: {path:?}");
+        let id = IdentifiableToolchain::new(&path)?.id();
+        assert_eq!(
+            entry.file_name().to_string_lossy(),
+            id,
+            "pool entry does not match its contents",
+        );
+    }
+    Ok(())
+}
+
+/// Asserts that every pool entry is referenced by some link.
+pub fn assert_all_referenced(ctx: &AppCtx) -> Result<()> {
+    let mut referenced = vec![];
+    for entry in ctx.rynzland_home.join("toolchains").read_dir()? {
+        referenced.push(resolve_link(&entry?.path())?.canonicalize()?);
+    }
+    for entry in ctx.rustup_home.join("toolchains").read_dir()? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let path = entry.path().canonicalize()?;
+            assert!(
+                referenced.contains(&path),
+                "unreferenced entry left: {path:?}"
+            );
+        }
     }
+    Ok(())
 }
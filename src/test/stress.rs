@@ -0,0 +1,137 @@
+//! Multi-process stress test of the transaction protocol.
+//!
+//! The test binary re-executes itself as several worker processes, each
+//! running randomized transactions against the same home directory with a
+//! [`FakeBackend`]. Once all of them have exited, the home is checked for
+//! consistency.
+
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use gix_lock::acquire::Fail;
+
+use super::prelude::*;
+use crate::{
+    AddSubcmd, CompAddSubcmd, CompRmSubcmd, Ctx as AppCtx, GcSubcmd, Result, RmSubCmd,
+    backend::FakeBackend,
+};
+
+/// The home directory shared by the workers, whose presence also tells a
+/// worker apart from a regular run of [`worker`].
+const HOME_ENV: &str = "RYNZLAND_STRESS_HOME";
+
+/// The seed of the transactions, which is random by default.
+const SEED_ENV: &str = "RYNZLAND_STRESS_SEED";
+
+const WORKERS: u64 = 8;
+const OPS_PER_WORKER: usize = 50;
+const LATENCY: Duration = Duration::from_millis(20);
+
+const TOOLCHAINS: &[&str] = &["stable", "beta", "1.80", "1.80.0", "1.81.0"];
+const SOURCES: &[Option<&str>] = &[None, Some("1.80.0"), Some("1.81.0")];
+const COMPONENTS: &[&str] = &["cargo", "clippy", "rustfmt"];
+
+/// A xorshift pseudo-random number generator, good enough for picking
+/// transactions reproducibly.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // NOTE: The state must never be zero.
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        let len = u64::try_from(items.len()).unwrap();
+        &items[usize::try_from(self.next() % len).unwrap()]
+    }
+}
+
+#[test]
+fn multi_process() -> Result<()> {
+    let seed = match env::var(SEED_ENV) {
+        Ok(seed) => seed.parse()?,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    eprintln!("stress test seed: {seed} (set `{SEED_ENV}` to reproduce)");
+
+    let ctx = Ctx::setup_fake(FakeBackend::new())?;
+    let workers = (0..WORKERS)
+        .map(|i| {
+            Ok(respawn("test::stress::worker")?
+                .env(HOME_ENV, ctx.home())
+                .env(SEED_ENV, seed.wrapping_add(i).to_string())
+                .spawn()?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for worker in workers {
+        let output = worker.wait_with_output()?;
+        assert!(
+            output.status.success(),
+            "worker failed: {}",
+            String::from_utf8_lossy(&output.stderr),
+        );
+    }
+
+    let app_ctx = ctx.app_ctx();
+    assert_consistent(&app_ctx)?;
+    GcSubcmd { unused_days: None }.run(&app_ctx)?;
+    assert_consistent(&app_ctx)?;
+    assert_all_referenced(&app_ctx)?;
+
+    drop(ctx);
+    Ok(())
+}
+
+#[test]
+#[ignore = "only meaningful as a child process of `multi_process`"]
+fn worker() -> Result<()> {
+    let Some(home) = env::var_os(HOME_ENV) else {
+        return Ok(());
+    };
+    let mut rng = Rng::new(env::var(SEED_ENV)?.parse()?);
+    let ctx = AppCtx::new(home)
+        .with_backend(FakeBackend::new().with_latency(LATENCY))
+        .with_gc_lock_backoff(Fail::AfterDurationWithBackoff(Duration::from_secs(5)));
+
+    for _ in 0..OPS_PER_WORKER {
+        let toolchain = (*rng.pick(TOOLCHAINS)).to_owned();
+        let components = vec![(*rng.pick(COMPONENTS)).to_owned()];
+        let res = match rng.next() % 8 {
+            0..=2 => AddSubcmd {
+                toolchain,
+                source: rng.pick(SOURCES).map(Into::into),
+                components: vec![],
+                allow_downgrade: false,
+            }
+            .run(&ctx),
+            3 => RmSubCmd { toolchain }.run(&ctx),
+            4 => CompAddSubcmd {
+                toolchain,
+                components,
+            }
+            .run(&ctx),
+            5 => CompRmSubcmd {
+                toolchain,
+                components,
+            }
+            .run(&ctx),
+            _ => GcSubcmd { unused_days: None }.run(&ctx),
+        };
+        // NOTE: Transactions are expected to fail when racing with each other, as
+        // long as they leave the home consistent.
+        if let Err(e) = res {
+            eprintln!("transaction failed: {e:#}");
+        }
+    }
+    Ok(())
+}
@@ -1,25 +1,34 @@
 use std::{
     borrow::ToOwned,
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
     fs,
     hash::{Hash, Hasher},
     path::Path,
     sync::LazyLock,
 };
 
-use anyhow::{self, Context, Result};
+use anyhow::{self, Result};
 use tracing::info;
 use twox_hash::XxHash64;
 
 use crate::{
-    rustup,
+    Config, Ctx, Error,
     util::{self, HashEncoder, qualify_with_target},
 };
 
-static CHANNEL_MANIFEST_SUBPATH: LazyLock<&'static Path> =
+mod manifest;
+mod name;
+
+pub use self::{
+    manifest::Manifest,
+    name::{Channel, ToolchainDesc, ToolchainName},
+};
+
+pub static CHANNEL_MANIFEST_SUBPATH: LazyLock<&'static Path> =
     LazyLock::new(|| Path::new("lib/rustlib/multirust-channel-manifest.toml"));
 
-static COMPONENTS_SUBPATH: LazyLock<&'static Path> =
+pub static COMPONENTS_SUBPATH: LazyLock<&'static Path> =
     LazyLock::new(|| Path::new("lib/rustlib/components"));
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -27,59 +36,227 @@ pub struct IdentifiableToolchain {
     /// The value of `pkg.rust.version` in the channel manifest.
     pub rust_ver: String,
 
+    /// The value of `date` in the channel manifest, if any.
+    pub date: Option<String>,
+
     /// The items in the `components` file.
     // TODO: Investigate whether host targets need to be normalized,
     // as well as whether `multirust-config.toml` should be used instead.
     pub components: BTreeSet<String>,
+
+    /// The package hashes of the `components` listed in the channel manifest,
+    /// which tell rebuilds of the same version apart.
+    pub hashes: BTreeMap<String, String>,
+
+    /// The version of the ID scheme [`Self::id`] follows.
+    pub scheme: u32,
 }
 
-pub fn resolve_channel(channel: &str, components: &[String]) -> Result<IdentifiableToolchain> {
-    let temp_dir = tempfile::Builder::new().prefix("rynzland").tempdir()?;
-    let temp_dir = temp_dir.path();
-    fs::create_dir_all(temp_dir)?;
+/// How many days [`resolve_newest_available`] goes back at most.
+const MAX_DOWNGRADE_DAYS: usize = 30;
 
-    let manifest_url = rustup::manifest_url(channel);
-    let manifest_path = temp_dir.join("multirust-channel-manifest.toml");
-    info!("downloading manifest from {manifest_url}...");
-    util::download_file(&manifest_url, &manifest_path)?;
-    let rust_ver = rust_ver_from_manifest(&manifest_path)?;
+/// Identifies the toolchain `desc` with the given `components`, failing with
+/// [`Error::ComponentsUnavailable`] if any of them is missing from its
+/// manifest.
+pub fn resolve_channel(
+    ctx: &Ctx,
+    desc: &ToolchainDesc,
+    components: &[String],
+) -> Result<IdentifiableToolchain> {
+    let manifest = fetch_manifest(ctx, desc)?;
+    let scheme = Config::load(&ctx.rynzland_home)?.id_scheme();
+    resolve_manifest(&manifest, desc, components, scheme)
+}
 
-    let components = match components {
-        [] => ["rustc", "cargo", "rust-std"]
-            .into_iter()
-            .chain(
-                util::BUILD_TARGET
-                    .ends_with("-pc-windows-gnu")
-                    .then_some("rust-mingw"),
-            )
-            .map(|s| qualify_with_target(s).to_string())
+/// Like [`resolve_channel`], but falls back to the newest archived nightly
+/// having all the `components` if `desc` is an undated nightly missing some of
+/// them, returning the description of the fallback as well.
+pub fn resolve_newest_available(
+    ctx: &Ctx,
+    desc: &ToolchainDesc,
+    components: &[String],
+) -> Result<(ToolchainDesc, IdentifiableToolchain)> {
+    let manifest = fetch_manifest(ctx, desc)?;
+    let scheme = Config::load(&ctx.rynzland_home)?.id_scheme();
+    let err = match resolve_manifest(&manifest, desc, components, scheme) {
+        Ok(id) => return Ok((desc.clone(), id)),
+        Err(e) if desc.channel == Channel::Nightly && desc.date.is_none() => e,
+        Err(e) => return Err(e),
+    };
+    if !matches!(Error::find(&err), Some(Error::ComponentsUnavailable { .. })) {
+        return Err(err);
+    }
+
+    let mut date = manifest.date()?.to_owned();
+    for _ in 0..MAX_DOWNGRADE_DAYS {
+        date = day_before(&date).ok_or_else(|| anyhow::anyhow!("invalid date `{date}`"))?;
+        let dated = ToolchainDesc {
+            date: Some(date.clone()),
+            ..desc.clone()
+        };
+        // NOTE: Some days might not have been archived at all, while any other
+        // failure, such as an interruption, would be hidden by skipping it.
+        let manifest = match fetch_manifest(ctx, &dated) {
+            Ok(manifest) => manifest,
+            Err(e) if matches!(Error::find(&e), Some(Error::ManifestNotFound(_))) => {
+                info!("skipping {dated}: {e:#}");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if let Ok(id) = resolve_manifest(&manifest, &dated, components, scheme) {
+            info!("falling back to {dated}...");
+            return Ok((dated, id));
+        }
+    }
+    Err(err)
+}
+
+fn fetch_manifest(ctx: &Ctx, desc: &ToolchainDesc) -> Result<Manifest> {
+    let temp_dir = tempfile::Builder::new().prefix("rynzland").tempdir()?;
+    let manifest_path = temp_dir.path().join("multirust-channel-manifest.toml");
+    ctx.backend.fetch_manifest(ctx, desc, &manifest_path)?;
+    Manifest::load(&manifest_path)
+}
+
+fn resolve_manifest(
+    manifest: &Manifest,
+    desc: &ToolchainDesc,
+    components: &[String],
+    scheme: u32,
+) -> Result<IdentifiableToolchain> {
+    let components: Vec<_> = match components {
+        [] => default_components().collect(),
+        cs => cs
+            .iter()
+            .map(|s| manifest.normalize(&qualify_with_target(s)))
             .collect(),
-        cs => cs.iter().map(|s| qualify_with_target(s).into()).collect(),
     };
+    check_available(manifest, &desc.to_string(), &components)?;
+    Ok(IdentifiableToolchain::from_manifest(manifest, components)?.with_scheme(scheme))
+}
+
+/// Fails with [`Error::ComponentsUnavailable`] if any of the (qualified)
+/// `components` of `toolchain` is missing from its `manifest`.
+pub fn check_available(manifest: &Manifest, toolchain: &str, components: &[String]) -> Result<()> {
+    let missing = manifest.missing_components(components);
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(Error::ComponentsUnavailable {
+        toolchain: toolchain.to_owned(),
+        components: missing.into_iter().map(ToOwned::to_owned).collect(),
+    }
+    .into())
+}
+
+/// Returns the day before `date` in the `YYYY-MM-DD` format.
+fn day_before(date: &str) -> Option<String> {
+    let mut parts = date.splitn(3, '-').map(str::parse::<u32>);
+    let (mut y, mut m, mut d) = (
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+    );
+    if d > 1 {
+        d -= 1;
+    } else {
+        if m > 1 {
+            m -= 1;
+        } else {
+            (y, m) = (y.checked_sub(1)?, 12);
+        }
+        let leap = y % 4 == 0 && (y % 100 != 0 || y % 400 == 0);
+        d = match m {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+    }
+    Some(format!("{y:04}-{m:02}-{d:02}"))
+}
 
-    Ok(IdentifiableToolchain {
-        rust_ver,
-        components,
-    })
+/// Returns the (qualified) components installed by the minimal profile.
+pub fn default_components() -> impl Iterator<Item = String> {
+    ["rustc", "cargo", "rust-std"]
+        .into_iter()
+        .chain(
+            util::BUILD_TARGET
+                .ends_with("-pc-windows-gnu")
+                .then_some("rust-mingw"),
+        )
+        .map(|s| qualify_with_target(s).into_owned())
 }
 
 impl IdentifiableToolchain {
+    /// The newest version of the ID scheme, which is appended to the IDs as
+    /// `-v{ID_SCHEME}` like every version but the first one.
+    ///
+    /// The first version only covers `pkg.rust.version` and the components,
+    /// while the second one also covers the manifest date and the package
+    /// hashes.
+    pub const ID_SCHEME: u32 = 2;
     pub const SEED: u64 = 0xfeed_c001_1ced_7ea5;
 
+    /// Identifies the toolchain in the pool entry at `toolchain`, following
+    /// the ID scheme the entry is named after.
     pub fn new(toolchain: &Path) -> Result<Self> {
         let manifest_path = toolchain.join(*CHANNEL_MANIFEST_SUBPATH);
-        let rust_ver = rust_ver_from_manifest(&manifest_path)?;
+        let manifest = Manifest::load(&manifest_path)?;
 
         let components_path = toolchain.join(*COMPONENTS_SUBPATH);
         let components = fs::read_to_string(components_path)?;
-        let components = components.lines().map(ToOwned::to_owned).collect();
+        let scheme = toolchain
+            .file_name()
+            .map_or(1, |it| Self::id_scheme(&it.to_string_lossy()));
+        Ok(
+            Self::from_manifest(&manifest, components.lines().map(ToOwned::to_owned))?
+                .with_scheme(scheme),
+        )
+    }
 
+    /// Identifies the toolchain of `manifest` with the (qualified)
+    /// `components`, following the first ID scheme.
+    pub fn from_manifest(
+        manifest: &Manifest,
+        components: impl IntoIterator<Item = String>,
+    ) -> Result<Self> {
+        // NOTE: Entries installed before a rename would otherwise be told apart
+        // from those installed after it.
+        let components = components
+            .into_iter()
+            .map(|c| manifest.normalize(&c))
+            .collect::<BTreeSet<_>>();
+        let hashes = components
+            .iter()
+            .filter_map(|c| Some((c.clone(), manifest.pkg_hash(c)?.to_owned())))
+            .collect();
         Ok(Self {
-            rust_ver,
+            rust_ver: manifest.rust_ver()?,
+            date: manifest.date().ok().map(ToOwned::to_owned),
             components,
+            hashes,
+            scheme: 1,
         })
     }
 
+    /// Makes [`Self::id`] follow the ID scheme `scheme`.
+    #[must_use]
+    pub const fn with_scheme(mut self, scheme: u32) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    /// Returns the version of the ID scheme `id` follows.
+    #[must_use]
+    pub fn id_scheme(id: &str) -> u32 {
+        id.rsplit_once("-v")
+            .and_then(|(_, scheme)| scheme.parse().ok())
+            .unwrap_or(1)
+    }
+
+    #[must_use]
     pub fn id(&self) -> String {
         let ver = &self.rust_ver;
 
@@ -95,36 +272,73 @@ impl IdentifiableToolchain {
             short_ver.to_owned() + "-"
         };
 
-        let hash = XxHash64::oneshot(Self::SEED, ver.as_bytes());
-        id.push_str(&HashEncoder::encode(hash));
+        let mut hasher = XxHash64::with_seed(Self::SEED);
+        hasher.write(ver.as_bytes());
+        if self.scheme > 1
+            && let Some(date) = &self.date
+        {
+            date.hash(&mut hasher);
+        }
+        id.push_str(&HashEncoder::encode(hasher.finish()));
 
         id.push('-');
 
         let mut hasher = XxHash64::with_seed(Self::SEED);
         self.components.hash(&mut hasher);
+        if self.scheme > 1 && !self.hashes.is_empty() {
+            self.hashes.hash(&mut hasher);
+        }
         id.push_str(&HashEncoder::encode(hasher.finish()));
 
+        if self.scheme > 1 {
+            write!(id, "-v{}", self.scheme).unwrap();
+        }
         id
     }
 }
 
-pub fn rust_ver_from_manifest(manifest_path: &Path) -> Result<String> {
-    fn enter_table<'v>(table: &'v toml::Value, key: &str) -> Result<&'v toml::Value> {
-        table
-            .as_table()
-            .context("expecting a table")?
-            .get(key)
-            .with_context(|| format!("failed to find item with key '{key}'"))
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let manifest = fs::read_to_string(manifest_path)
-        .with_context(|| format!("when reading manifest at {}", manifest_path.display()))?;
-    let manifest: toml::Value = toml::from_str(&manifest)?;
+    #[test]
+    fn id_from_manifest() -> Result<()> {
+        let id = |scheme: u32, date: &str, hash: &str| -> Result<String> {
+            let manifest = format!(
+                "date = \"{date}\"\n[pkg.rust]\nversion = \"1.81.0 (eeb90cda1 2024-09-04)\"\n\
+                 [pkg.cargo.target.\"*\"]\navailable = true\nhash = \"{hash}\"\n",
+            );
+            let comps = ["cargo".to_owned(), "rustc".to_owned()];
+            Ok(
+                IdentifiableToolchain::from_manifest(&manifest.parse()?, comps)?
+                    .with_scheme(scheme)
+                    .id(),
+            )
+        };
+
+        // NOTE: The first scheme must keep naming the entries as it always has.
+        let original = id(1, "2024-09-05", "aaaa")?;
+        assert_eq!(original, "1.81.0-ckxd6nk9ssa9t-69uh3n6wthctx");
+        assert_eq!(IdentifiableToolchain::id_scheme(&original), 1);
+        assert_eq!(original, id(1, "2024-09-06", "bbbb")?);
 
-    Ok(enter_table(&manifest, "pkg")
-        .and_then(|it| enter_table(it, "rust"))
-        .and_then(|it| enter_table(it, "version"))
-        .and_then(|it| it.as_str().context("expected a string"))
-        .context("failed to get `pkg.rust.version` from channel manifest")?
-        .to_owned())
+        let original = id(2, "2024-09-05", "aaaa")?;
+        assert!(original.starts_with("1.81.0-"));
+        assert_eq!(IdentifiableToolchain::id_scheme(&original), 2);
+        assert_eq!(original, id(2, "2024-09-05", "aaaa")?);
+        assert_ne!(original, id(2, "2024-09-06", "aaaa")?, "republished");
+        assert_ne!(original, id(2, "2024-09-05", "bbbb")?, "rebuilt");
+        Ok(())
+    }
+
+    #[test]
+    fn days_before() {
+        let day_before = |date| day_before(date).unwrap();
+        assert_eq!(day_before("2025-06-02"), "2025-06-01");
+        assert_eq!(day_before("2025-06-01"), "2025-05-31");
+        assert_eq!(day_before("2025-01-01"), "2024-12-31");
+        assert_eq!(day_before("2024-03-01"), "2024-02-29");
+        assert_eq!(day_before("2100-03-01"), "2100-02-28");
+        assert!(super::day_before("yesterday").is_none());
+    }
 }
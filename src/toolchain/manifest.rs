@@ -0,0 +1,120 @@
+use std::{fs, path::Path, str::FromStr};
+
+use anyhow::{Context, Result};
+
+use crate::util::{self, BUILD_TARGET};
+
+/// A channel manifest, i.e. `channel-rust-*.toml`.
+#[derive(Debug, Clone)]
+pub struct Manifest(toml::Value);
+
+impl FromStr for Manifest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Self(toml::from_str(s)?))
+    }
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        fs::read_to_string(path)
+            .with_context(|| format!("when reading manifest at {}", path.display()))?
+            .parse()
+    }
+
+    /// Returns the value of `pkg.rust.version`.
+    pub fn rust_ver(&self) -> Result<String> {
+        Ok(self
+            .get(&["pkg", "rust", "version"])
+            .and_then(|it| it.as_str().context("expected a string"))
+            .context("failed to get `pkg.rust.version` from channel manifest")?
+            .to_owned())
+    }
+
+    /// Returns the value of `date`, i.e. the day the manifest was published.
+    pub fn date(&self) -> Result<&str> {
+        self.get(&["date"])
+            .and_then(|it| it.as_str().context("expected a string"))
+            .context("failed to get `date` from channel manifest")
+    }
+
+    /// Returns those among the (qualified) `components` that are not available
+    /// for the build target.
+    #[must_use]
+    pub fn missing_components<'c>(&self, components: &'c [String]) -> Vec<&'c str> {
+        components
+            .iter()
+            .map(String::as_str)
+            .filter(|comp| !self.is_available(comp))
+            .collect()
+    }
+
+    /// Returns the current name of the (possibly qualified) `comp`, following
+    /// the `renames` table, e.g. `rustfmt-preview` becomes `rustfmt`.
+    #[must_use]
+    pub fn normalize(&self, comp: &str) -> String {
+        let pkg = util::unqualify(comp);
+        let Some(renamed) = self
+            .get(&["renames", pkg, "to"])
+            .ok()
+            .and_then(toml::Value::as_str)
+        else {
+            return comp.to_owned();
+        };
+        if pkg.len() < comp.len() {
+            util::qualify_with_target(renamed).into_owned()
+        } else {
+            renamed.to_owned()
+        }
+    }
+
+    /// Returns the hash of the package tarball of the (qualified) `comp`, if
+    /// listed.
+    #[must_use]
+    pub fn pkg_hash(&self, comp: &str) -> Option<&str> {
+        self.target_field(comp, "hash")?.as_str()
+    }
+
+    /// Returns the URLs and hashes of the package tarballs of the (qualified)
+    /// `comp` in every compression format listed, from the most preferred by
+    /// rustup to the least.
+    #[must_use]
+    pub fn pkg_bins(&self, comp: &str) -> Vec<(&str, &str)> {
+        [
+            ("zst_url", "zst_hash"),
+            ("xz_url", "xz_hash"),
+            ("url", "hash"),
+        ]
+        .into_iter()
+        .filter_map(|(url, hash)| {
+            let url = self.target_field(comp, url)?.as_str()?;
+            Some((url, self.target_field(comp, hash)?.as_str()?))
+        })
+        .collect()
+    }
+
+    fn is_available(&self, comp: &str) -> bool {
+        self.target_field(comp, "available")
+            .is_some_and(|it| it.as_bool() == Some(true))
+    }
+
+    /// Returns the `key` of the build target's entry of the (qualified) `comp`.
+    fn target_field(&self, comp: &str, key: &str) -> Option<&toml::Value> {
+        let pkg = util::unqualify(comp);
+        // NOTE: Target-independent packages such as `rust-src` are listed under `*`.
+        [BUILD_TARGET, "*"]
+            .into_iter()
+            .find_map(|target| self.get(&["pkg", pkg, "target", target, key]).ok())
+    }
+
+    fn get(&self, keys: &[&str]) -> Result<&toml::Value> {
+        keys.iter().try_fold(&self.0, |table, key| {
+            table
+                .as_table()
+                .context("expecting a table")?
+                .get(*key)
+                .with_context(|| format!("failed to find item with key '{key}'"))
+        })
+    }
+}
@@ -0,0 +1,316 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use crate::{Error, util::BUILD_TARGET};
+
+/// A release channel, which is either a named one or a specific version.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Stable,
+    Beta,
+    Nightly,
+
+    /// A version like `1.80`, `1.80.0` or `1.80.0-beta.1`.
+    Version(String),
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Stable => "stable",
+            Self::Beta => "beta",
+            Self::Nightly => "nightly",
+            Self::Version(ver) => ver,
+        })
+    }
+}
+
+/// The description of an official toolchain, mirroring rustup's.
+///
+/// Its string form is the qualified toolchain name, such as
+/// `nightly-2025-06-01-aarch64-apple-darwin`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ToolchainDesc {
+    pub channel: Channel,
+
+    /// The archive date in the `YYYY-MM-DD` format.
+    pub date: Option<String>,
+
+    /// The target triple, which defaults to the build target.
+    pub target: String,
+}
+
+impl ToolchainDesc {
+    /// Returns the name of the channel manifest without the target, such as
+    /// `nightly-2025-06-01`.
+    #[must_use]
+    pub fn manifest_name(&self) -> String {
+        let mut name = self.channel.to_string();
+        if let Some(date) = &self.date {
+            name.push('-');
+            name.push_str(date);
+        }
+        name
+    }
+
+    /// Returns `self` if it targets the build target, failing with
+    /// [`Error::InvalidToolchainName`] otherwise, since the components are
+    /// always qualified with the build target.
+    pub fn check_host(&self) -> Result<&Self, Error> {
+        if self.target == BUILD_TARGET {
+            return Ok(self);
+        }
+        Err(Error::InvalidToolchainName {
+            name: self.to_string(),
+            reason: format!("its target is not the host target `{BUILD_TARGET}`"),
+        })
+    }
+}
+
+impl fmt::Display for ToolchainDesc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.manifest_name(), self.target)
+    }
+}
+
+/// A toolchain name that has been validated to be safe for use as a file
+/// name under the `toolchains` directories.
+///
+/// Its string form is the qualified name, which is the file name of the
+/// corresponding link.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ToolchainName {
+    /// A name starting with a known channel.
+    Official(ToolchainDesc),
+
+    /// Any other name, without the build target suffix.
+    Custom(String),
+}
+
+impl ToolchainName {
+    /// Returns the description of the official toolchain this name refers to,
+    /// failing for custom names which can't be installed from a channel.
+    pub fn desc(&self) -> Result<&ToolchainDesc, Error> {
+        match self {
+            Self::Official(desc) => Ok(desc),
+            Self::Custom(name) => Err(Error::InvalidToolchainName {
+                name: name.clone(),
+                reason: "it doesn't refer to an official channel".to_owned(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for ToolchainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Official(desc) => desc.fmt(f),
+            Self::Custom(name) => write!(f, "{name}-{BUILD_TARGET}"),
+        }
+    }
+}
+
+impl FromStr for ToolchainName {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| Error::InvalidToolchainName {
+            name: name.to_owned(),
+            reason: reason.to_owned(),
+        };
+        if name.is_empty() {
+            return Err(invalid("it is empty"));
+        }
+        // NOTE: On Windows, `C:foo` is relative to the current directory of the
+        // drive, and `foo:bar` addresses an alternate data stream.
+        if name.contains(['/', '\\', ':']) || name.chars().any(char::is_control) {
+            return Err(invalid(
+                "it contains a path separator or a control character",
+            ));
+        }
+        if is_reserved_on_windows(name) {
+            return Err(invalid("it is a reserved device name on Windows"));
+        }
+        if name.starts_with('.') || name.contains("..") {
+            return Err(invalid("it could refer to another directory"));
+        }
+        // NOTE: These would be mistaken for in-flight links or locks in the pool.
+        let ext = Path::new(name).extension().unwrap_or_default();
+        if ext.eq_ignore_ascii_case("tmp") || ext.eq_ignore_ascii_case("lock") {
+            return Err(invalid("it ends with a reserved extension"));
+        }
+
+        let Some((channel, rest)) = split_channel(name) else {
+            let suffix = format!("-{BUILD_TARGET}");
+            return Ok(Self::Custom(
+                name.strip_suffix(&suffix).unwrap_or(name).to_owned(),
+            ));
+        };
+
+        let mut rest = rest.strip_prefix('-').unwrap_or(rest);
+        let date = split_date(rest).map(|(date, tail)| {
+            rest = tail.strip_prefix('-').unwrap_or(tail);
+            date.to_owned()
+        });
+        if !rest
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"_.-".contains(&b))
+        {
+            return Err(invalid("its target is not a valid target triple"));
+        }
+        Ok(Self::Official(ToolchainDesc {
+            channel,
+            date,
+            target: if rest.is_empty() { BUILD_TARGET } else { rest }.to_owned(),
+        }))
+    }
+}
+
+/// Returns whether `name` refers to a device on Windows, such as `NUL` or
+/// `com1.txt`, no matter the directory it's in.
+fn is_reserved_on_windows(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let stem = stem.to_ascii_uppercase();
+    if ["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"].contains(&stem.as_str()) {
+        return true;
+    }
+    // NOTE: The superscript digits count as well.
+    ["COM", "LPT"].iter().any(|dev| {
+        let mut n = stem.strip_prefix(dev).unwrap_or_default().chars();
+        matches!((n.next(), n.next()), (Some(d), None) if "123456789¹²³".contains(d))
+    })
+}
+
+/// Splits `name` into a known channel and the rest, which is either empty or
+/// starts with `-`.
+fn split_channel(name: &str) -> Option<(Channel, &str)> {
+    let split = |len: usize| {
+        let (channel, rest) = name.split_at(len);
+        (rest.is_empty() || rest.starts_with('-')).then_some((channel, rest))
+    };
+    for channel in [Channel::Stable, Channel::Beta, Channel::Nightly] {
+        if name.starts_with(&channel.to_string()) {
+            return split(channel.to_string().len()).map(|(_, rest)| (channel, rest));
+        }
+    }
+
+    // Versions like `1.80`, `1.80.0` or `1.80.0-beta.1`.
+    let digits = |s: &str| s.bytes().take_while(u8::is_ascii_digit).count();
+    let major = digits(name);
+    let minor = name[major..].strip_prefix('.').map_or(0, digits);
+    if major == 0 || minor == 0 {
+        return None;
+    }
+    let mut len = major + 1 + minor;
+    if let Some(patch) = name[len..].strip_prefix('.').map(digits)
+        && patch > 0
+    {
+        len += 1 + patch;
+    }
+    if let Some(rest) = name[len..].strip_prefix("-beta") {
+        len += "-beta".len();
+        if let Some(num) = rest.strip_prefix('.').map(digits)
+            && num > 0
+        {
+            len += 1 + num;
+        }
+    }
+    split(len).map(|(ver, rest)| (Channel::Version(ver.to_owned()), rest))
+}
+
+/// Splits a leading `YYYY-MM-DD` date off `s`.
+fn split_date(s: &str) -> Option<(&str, &str)> {
+    let date = s.get(..10)?;
+    let is_date = date.bytes().enumerate().all(|(i, b)| match i {
+        4 | 7 => b == b'-',
+        _ => b.is_ascii_digit(),
+    });
+    let rest = &s[10..];
+    (is_date && (rest.is_empty() || rest.starts_with('-'))).then_some((date, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desc(name: &str) -> (String, Option<String>, String) {
+        let name = name.parse::<ToolchainName>().unwrap();
+        let ToolchainDesc {
+            channel,
+            date,
+            target,
+        } = name.desc().unwrap().clone();
+        (channel.to_string(), date, target)
+    }
+
+    #[test]
+    fn parse_valid() {
+        let s = |it: &str| Some(it.to_owned());
+        let host = || BUILD_TARGET.to_owned();
+        assert_eq!(desc("stable"), ("stable".into(), None, host()));
+        assert_eq!(desc("1.80"), ("1.80".into(), None, host()));
+        assert_eq!(desc("1.80.0"), ("1.80.0".into(), None, host()));
+        assert_eq!(
+            desc("1.80.0-beta.1"),
+            ("1.80.0-beta.1".into(), None, host())
+        );
+        assert_eq!(
+            desc("nightly-2025-06-01"),
+            ("nightly".into(), s("2025-06-01"), host()),
+        );
+        assert_eq!(
+            desc("beta-2025-01-01-aarch64-apple-darwin"),
+            (
+                "beta".into(),
+                s("2025-01-01"),
+                "aarch64-apple-darwin".into()
+            ),
+        );
+        assert_eq!(
+            desc("1.80-x86_64-pc-windows-msvc"),
+            ("1.80".into(), None, "x86_64-pc-windows-msvc".into()),
+        );
+
+        for name in ["my-toolchain", "stabler"] {
+            let parsed = name.parse::<ToolchainName>().unwrap();
+            assert_eq!(parsed, ToolchainName::Custom(name.into()));
+            assert!(parsed.desc().is_err());
+        }
+
+        for name in ["stable", "custom", "nightly-2025-06-01"] {
+            let name = format!("{name}-{BUILD_TARGET}");
+            assert_eq!(name.parse::<ToolchainName>().unwrap().to_string(), name);
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for name in [
+            "",
+            "..",
+            ".",
+            "../../something",
+            "stable/../..",
+            "a\\b",
+            "nightly-..",
+            "stable.tmp",
+            "stable.TMP",
+            "pool_gc.lock",
+            "stable-x86_64 linux",
+            "new\nline",
+            "C:foo",
+            "stable:stream",
+            "nul",
+            "CON.txt",
+            "com1",
+            "LPT9.tar.gz",
+        ] {
+            assert!(
+                matches!(
+                    name.parse::<ToolchainName>(),
+                    Err(Error::InvalidToolchainName { .. }),
+                ),
+                "`{name}` should be rejected",
+            );
+        }
+    }
+}
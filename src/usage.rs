@@ -0,0 +1,79 @@
+//! The usage statistics of the toolchain links, recorded by `run`.
+
+use std::{collections::BTreeMap, fs, io::Write as _, path::Path, time::Duration};
+
+use anyhow::{Context, Result};
+use gix_lock::acquire::Fail;
+use serde::{Deserialize, Serialize};
+
+use crate::entry;
+
+/// The name of the usage database in `ctx.rynzland_home`.
+pub const USAGE_DB: &str = "usage.toml";
+
+/// How long an update waits for another one to finish before giving up.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The contents of [`USAGE_DB`], keyed by the (qualified) toolchain names.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageDb(BTreeMap<String, LinkUsage>);
+
+/// The usage statistics of a single toolchain link.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkUsage {
+    /// When the toolchain has last been run, in seconds since the Unix epoch.
+    pub last_used: u64,
+
+    /// How many times the toolchain has been run.
+    pub count: u64,
+}
+
+impl UsageDb {
+    /// Reads the database in `rynzland_home`, returning an empty one if there
+    /// is none.
+    pub fn load(rynzland_home: &Path) -> Result<Self> {
+        let path = rynzland_home.join(USAGE_DB);
+        match fs::read_to_string(&path) {
+            Ok(it) => toml::from_str(&it)
+                .with_context(|| format!("when reading usage database at {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns the statistics of `toolchain`, if it has ever been run.
+    #[must_use]
+    pub fn get(&self, toolchain: &str) -> Option<LinkUsage> {
+        self.0.get(toolchain).copied()
+    }
+
+    /// Records a run of `toolchain` right now.
+    pub fn record(rynzland_home: &Path, toolchain: &str) -> Result<()> {
+        Self::update(rynzland_home, |db| {
+            let usage = db.0.entry(toolchain.to_owned()).or_default();
+            usage.last_used = entry::unix_now();
+            usage.count += 1;
+        })
+    }
+
+    /// Forgets the statistics of the toolchains not satisfying `keep`.
+    pub fn retain(rynzland_home: &Path, mut keep: impl FnMut(&str) -> bool) -> Result<()> {
+        Self::update(rynzland_home, |db| db.0.retain(|name, _| keep(name)))
+    }
+
+    /// Applies `f` to the database in `rynzland_home` while holding its lock,
+    /// replacing it atomically.
+    fn update(rynzland_home: &Path, f: impl FnOnce(&mut Self)) -> Result<()> {
+        let path = rynzland_home.join(USAGE_DB);
+        let mut lock = gix_lock::File::acquire_to_update_resource(
+            &path,
+            Fail::AfterDurationWithBackoff(LOCK_TIMEOUT),
+            None,
+        )?;
+        let mut db = Self::load(rynzland_home)?;
+        f(&mut db);
+        lock.write_all(toml::to_string(&db)?.as_bytes())?;
+        lock.commit().map_err(|e| e.error)?;
+        Ok(())
+    }
+}
@@ -1,57 +1,237 @@
 use std::{
     borrow::Cow,
+    collections::{HashSet, VecDeque},
     fs::{self, File},
-    io,
+    io::{self, BufRead, BufReader, IsTerminal, Read, Write},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    time::Duration,
 };
 
 use anyhow::{Context, Result};
+use tracing::{info, warn};
+
+use crate::{Error, signal};
 
 pub const BUILD_TARGET: &str = env!("TARGET");
 
+/// How often a running child process is checked for termination signals.
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How many trailing lines of the child's stderr are kept by
+/// [`CommandExt::run_streaming`] for error reports.
+const STDERR_TAIL_LINES: usize = 40;
+
 pub trait CommandExt {
+    /// Runs the command to completion, capturing all of its output and
+    /// reporting it only if the command fails.
     fn run_checked(&mut self) -> Result<()>;
+
+    /// Like [`CommandExt::run_checked`], but forwards the output of the
+    /// command to `tracing` line by line as it is produced, keeping only the
+    /// tail of its stderr for error reports.
+    ///
+    /// If our stderr is a terminal, the command's stderr is attached to it
+    /// directly instead, so that progress bars are drawn as usual, in which
+    /// case the error reports refer to the output above.
+    ///
+    /// This is preferred for long-running commands such as `rustup install`.
+    fn run_streaming(&mut self) -> Result<()>;
+
+    /// Runs the command to completion with the standard streams inherited,
+    /// returning its exit status as is.
+    ///
+    /// This is meant for shims, which are not killed upon termination signals
+    /// either, as they receive those signals themselves.
+    fn run_inherited(&mut self) -> Result<ExitStatus>;
 }
 
 impl CommandExt for Command {
     fn run_checked(&mut self) -> Result<()> {
-        let program = self.get_program().to_string_lossy();
-        let args = self
-            .get_args()
-            .map(|a| a.to_string_lossy())
-            .collect::<Vec<_>>()
-            .join(" ");
-        let envs = self
-            .get_envs()
-            .filter_map(|(k, v)| {
-                let k = k.to_string_lossy();
-                let v = v.map(|v| v.to_string_lossy())?;
-                Some(format!("{k}={v}"))
+        let cmd_str = command_str(self);
+        info!("running: {cmd_str}");
+
+        let mut child = spawn(self, &cmd_str, Stdio::piped())?;
+        let read_all = |mut pipe: Box<dyn Read + Send>| {
+            thread::spawn(move || {
+                let mut buf = Vec::new();
+                _ = pipe.read_to_end(&mut buf);
+                buf
             })
-            .collect::<Vec<_>>()
-            .join(" ");
+        };
+        let stdout = read_all(Box::new(child.stdout.take().unwrap()));
+        let stderr = read_all(Box::new(child.stderr.take().unwrap()));
 
-        let cmd_str = if envs.is_empty() {
-            format!("{program} {args}")
+        if !wait_interruptible(&mut child, &cmd_str)?.success() {
+            let stdout = stdout.join().unwrap_or_default();
+            let stdout = String::from_utf8_lossy(&stdout);
+            let stderr = stderr.join().unwrap_or_default();
+            let stderr = String::from_utf8_lossy(&stderr);
+            return Err(Error::RustupFailed {
+                cmd: cmd_str,
+                output: format!("STDOUT:\n{stdout}\n\nSTDERR:\n{stderr}"),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn run_streaming(&mut self) -> Result<()> {
+        let cmd_str = command_str(self);
+        info!("running: {cmd_str}");
+
+        // NOTE: Programs like rustup only draw progress bars on terminals.
+        let stderr = if io::stderr().is_terminal() {
+            Stdio::inherit()
         } else {
-            format!("{envs} {program} {args}")
+            Stdio::piped()
         };
+        let mut child = spawn(self, &cmd_str, stderr)?;
+        let program = Path::new(self.get_program())
+            .file_stem()
+            .map_or_else(String::new, |it| it.to_string_lossy().into_owned());
 
-        tracing::info!("running: {cmd_str}");
+        let stdout = child.stdout.take().unwrap();
+        let stdout = {
+            let program = program.clone();
+            thread::spawn(move || {
+                for_each_line(stdout, |line| info!("[{program}] {line}"));
+            })
+        };
+
+        let stderr = child.stderr.take().map(|stderr| {
+            thread::spawn(move || {
+                let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+                for_each_line(stderr, |line| {
+                    info!("[{program}] {line}");
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line.to_owned());
+                });
+                tail
+            })
+        });
 
-        let output = self
-            .output()
-            .with_context(|| format!("failed to spawn command: {cmd_str}"))?;
+        let status = wait_interruptible(&mut child, &cmd_str)?;
+        _ = stdout.join();
+        let tail = stderr.map(|it| it.join().unwrap_or_default());
 
-        if !output.status.success() {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("command failed: {cmd_str}\n\nSTDOUT:\n{stdout}\n\nSTDERR:\n{stderr}");
+        if !status.success() {
+            let output = tail.map_or_else(
+                || "STDERR: see above".to_owned(),
+                |tail| {
+                    let tail = Vec::from(tail).join("\n");
+                    format!("STDERR (last {STDERR_TAIL_LINES} lines):\n{tail}")
+                },
+            );
+            return Err(Error::RustupFailed {
+                cmd: cmd_str,
+                output,
+            }
+            .into());
         }
 
         Ok(())
     }
+
+    fn run_inherited(&mut self) -> Result<ExitStatus> {
+        let cmd_str = command_str(self);
+        info!("running: {cmd_str}");
+        self.status()
+            .with_context(|| format!("failed to spawn command: {cmd_str}"))
+    }
+}
+
+/// Renders `cmd` in a shell-like form for logging.
+fn command_str(cmd: &Command) -> String {
+    let program = cmd.get_program().to_string_lossy();
+    let args = cmd
+        .get_args()
+        .map(|a| a.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let envs = cmd
+        .get_envs()
+        .filter_map(|(k, v)| {
+            let k = k.to_string_lossy();
+            let v = v.map(|v| v.to_string_lossy())?;
+            Some(format!("{k}={v}"))
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if envs.is_empty() {
+        format!("{program} {args}")
+    } else {
+        format!("{envs} {program} {args}")
+    }
+}
+
+/// Spawns `cmd` with its stdout piped and its stderr set to `stderr`.
+fn spawn(cmd: &mut Command, cmd_str: &str, stderr: Stdio) -> Result<Child> {
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(stderr)
+        .spawn()
+        .with_context(|| format!("failed to spawn command: {cmd_str}"))
+}
+
+/// Waits for `child` to exit, killing it if a termination signal arrives in
+/// the meantime.
+fn wait_interruptible(child: &mut Child, cmd_str: &str) -> Result<ExitStatus> {
+    // NOTE: We poll instead of blocking on `wait()` so that the child can be
+    // killed as soon as a termination signal arrives.
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if signal::is_interrupted() {
+            warn!("interrupted, killing: {cmd_str}");
+            _ = child.kill();
+            _ = child.wait();
+            return Err(
+                anyhow::Error::new(Error::Interrupted).context(format!("while running: {cmd_str}"))
+            );
+        }
+        thread::sleep(CHILD_POLL_INTERVAL);
+    }
+}
+
+/// Calls `f` on each non-empty line read from `reader`.
+///
+/// Both `\n` and `\r` are treated as line breaks, so that progress bars
+/// redrawing the same line are reported as they are updated.
+fn for_each_line(reader: impl Read, mut f: impl FnMut(&str)) {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok([]) | Err(_) => break,
+            Ok(buf) => buf,
+        };
+        let len = buf.len();
+        for &b in buf {
+            if b == b'\n' || b == b'\r' {
+                let s = String::from_utf8_lossy(&line);
+                let s = s.trim_end();
+                if !s.is_empty() {
+                    f(s);
+                }
+                line.clear();
+            } else {
+                line.push(b);
+            }
+        }
+        reader.consume(len);
+    }
+    let s = String::from_utf8_lossy(&line);
+    let s = s.trim_end();
+    if !s.is_empty() {
+        f(s);
+    }
 }
 
 pub fn qualify_with_target(toolchain: &str) -> Cow<'_, str> {
@@ -62,27 +242,159 @@ pub fn qualify_with_target(toolchain: &str) -> Cow<'_, str> {
     format!("{toolchain}{suffix}").into()
 }
 
+/// The inverse of [`qualify_with_target`], as rustup expects components to be
+/// named without the host when installing them.
+#[must_use]
+pub fn unqualify(comp: &str) -> &str {
+    comp.strip_suffix(&format!("-{BUILD_TARGET}"))
+        .unwrap_or(comp)
+}
+
 pub fn download_file(url: &str, dest: &Path) -> Result<()> {
+    download_file_inner(url, dest).map_err(|e| {
+        if Error::find(&e).is_some() {
+            return e;
+        }
+        // NOTE: The HTTP errors are kept as is, so that `is_not_found` can find them.
+        let source = match e.downcast::<ureq::Error>() {
+            Ok(e) => e.into(),
+            Err(e) => e.into(),
+        };
+        Error::DownloadFailed {
+            url: url.to_owned(),
+            source,
+        }
+        .into()
+    })
+}
+
+/// Returns whether `err` has been caused by the server responding with
+/// `404 Not Found`.
+#[must_use]
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    err.chain().any(|it| {
+        matches!(
+            it.downcast_ref::<ureq::Error>(),
+            Some(ureq::Error::StatusCode(404))
+        )
+    })
+}
+
+fn download_file_inner(url: &str, dest: &Path) -> Result<()> {
+    // How often the download progress is reported, in percent, or in bytes if
+    // the total length is unknown.
+    const PROGRESS_STEP_PERCENT: u64 = 10;
+    const PROGRESS_STEP_BYTES: u64 = 16 << 20;
+
     let mut resp = ureq::get(url).call()?;
+    let total = resp.body().content_length();
     let mut reader = resp.body_mut().as_reader();
     let mut dest = File::create(dest)?;
-    std::io::copy(&mut reader, &mut dest)?;
+
+    let mut buf = vec![0; 64 << 10];
+    let (mut done, mut reported) = (0, 0);
+    loop {
+        signal::check()?;
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        dest.write_all(&buf[..n])?;
+        done += n as u64;
+
+        let progress = total.map_or(done / PROGRESS_STEP_BYTES, |total| {
+            done * 100 / total.max(1) / PROGRESS_STEP_PERCENT
+        });
+        if progress > reported {
+            reported = progress;
+            if let Some(total) = total {
+                info!(
+                    "downloaded {} of {} ({}%)",
+                    human_size(done),
+                    human_size(total),
+                    done * 100 / total.max(1),
+                );
+            } else {
+                info!("downloaded {}", human_size(done));
+            }
+        }
+    }
     Ok(())
 }
 
-// https://stackoverflow.com/a/65192210
-pub fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()> {
-    fs::create_dir(&dst)?;
-    for entry in fs::read_dir(src)? {
+/// Returns the total size of the files under `path` in bytes, skipping those
+/// in `seen` and adding the rest to it, which tells hard links apart on Unix.
+pub fn dir_size(path: &Path, seen: &mut HashSet<(u64, u64)>) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
         let entry = entry?;
         let ty = entry.file_type()?;
         if ty.is_dir() {
-            copy_dir_all(entry.path(), dst.as_ref().join(entry.file_name()))?;
-        } else {
-            fs::copy(entry.path(), dst.as_ref().join(entry.file_name()))?;
+            size += dir_size(&entry.path(), seen)?;
+        } else if ty.is_file() {
+            let meta = entry.metadata()?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                if !seen.insert((meta.dev(), meta.ino())) {
+                    continue;
+                }
+            }
+            size += meta.len();
         }
     }
-    Ok(())
+    Ok(size)
+}
+
+/// Formats a byte count in binary units.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    #[allow(clippy::cast_precision_loss)]
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} {}", UNITS[0])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+pub fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()> {
+    fs::create_dir(&dst)?;
+    link_dir_contents(src, dst, &|_| true)
+}
+
+/// Recreates the contents of `src` in the existing directory `dst`,
+/// hard-linking the files except those whose paths relative to `src` satisfy
+/// `copy`. Files that cannot be hard-linked, e.g. across devices, are copied as
+/// well.
+///
+/// The linked files are shared with `src`, so they must never be modified in
+/// place afterwards.
+pub fn link_dir_contents(
+    src: impl AsRef<Path>,
+    dst: impl AsRef<Path>,
+    copy: &dyn Fn(&Path) -> bool,
+) -> io::Result<()> {
+    fn go(src: &Path, dst: &Path, rel: &Path, copy: &dyn Fn(&Path) -> bool) -> io::Result<()> {
+        for entry in fs::read_dir(src.join(rel))? {
+            let entry = entry?;
+            let rel = rel.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                fs::create_dir(dst.join(&rel))?;
+                go(src, dst, &rel, copy)?;
+            } else if copy(&rel) || fs::hard_link(entry.path(), dst.join(&rel)).is_err() {
+                fs::copy(entry.path(), dst.join(&rel))?;
+            }
+        }
+        Ok(())
+    }
+    go(src.as_ref(), dst.as_ref(), Path::new(""), copy)
 }
 
 pub fn with_tmp(path: &Path) -> PathBuf {
@@ -91,6 +403,64 @@ pub fn with_tmp(path: &Path) -> PathBuf {
     path.into()
 }
 
+/// Returns whether `path` has been created by [`with_tmp`].
+pub fn is_tmp(path: impl AsRef<Path>) -> bool {
+    path.as_ref().extension().is_some_and(|ext| ext == "tmp")
+}
+
+/// A guard that removes the registered FS entries in reverse order when
+/// dropped, unless [`Rollback::commit`] has been called in the meantime.
+///
+/// Only entries that are known to be owned by the current transaction should
+/// be registered, or we might end up removing another process's work.
+#[derive(Debug, Default)]
+#[must_use]
+pub struct Rollback {
+    paths: Vec<PathBuf>,
+}
+
+impl Rollback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, path: impl Into<PathBuf>) {
+        self.paths.push(path.into());
+    }
+
+    /// Declares the transaction successful, disarming the guard.
+    pub fn commit(mut self) {
+        self.paths.clear();
+    }
+}
+
+impl Drop for Rollback {
+    fn drop(&mut self) {
+        for path in self.paths.drain(..).rev() {
+            info!("rolling back {}...", path.display());
+            if let Err(e) = remove_any(&path) {
+                warn!("failed to roll back {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+/// Removes `path` whatever it is, without following soft links.
+pub fn remove_any(path: &Path) -> Result<()> {
+    let file_type = match path.symlink_metadata() {
+        Ok(meta) => meta.file_type(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if file_type.is_symlink() {
+        soft_unlink(path)
+    } else if file_type.is_dir() {
+        Ok(fs::remove_dir_all(path)?)
+    } else {
+        Ok(fs::remove_file(path)?)
+    }
+}
+
 pub struct HashEncoder;
 
 /// Creates a soft link from `link` to `original` (symlink on Unix, junction on
@@ -174,4 +544,61 @@ mod tests {
             [13, 13, 13, 13, 13]
         );
     }
+
+    #[test]
+    fn human_sizes() {
+        let sizes = [0, 1023, 1024, 20 << 20, 3 << 30].map(human_size);
+        assert_eq!(sizes, ["0 B", "1023 B", "1.0 KiB", "20.0 MiB", "3.0 GiB"]);
+    }
+
+    #[test]
+    fn split_lines() {
+        let mut lines = Vec::new();
+        for_each_line(&b"a\nb\r\n 10%\r 20%\rc"[..], |l| lines.push(l.to_owned()));
+        assert_eq!(lines, ["a", "b", " 10%", " 20%", "c"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streaming_failure() {
+        let err = Command::new("sh")
+            .args(["-c", "echo oops >&2; exit 3"])
+            .run_streaming()
+            .unwrap_err();
+        let Some(Error::RustupFailed { output, .. }) = Error::find(&err) else {
+            panic!("unexpected error: {err:?}");
+        };
+        if io::stderr().is_terminal() {
+            assert!(output.contains("see above"), "{output}");
+        } else {
+            assert!(output.ends_with("\noops"), "{output}");
+        }
+    }
+
+    #[test]
+    fn rollback() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let dir = tempdir.path().join("dir");
+        let link = tempdir.path().join("link");
+
+        let mut rollback = Rollback::new();
+        fs::create_dir(&dir)?;
+        rollback.push(&dir);
+        fs::write(dir.join("file"), "")?;
+        soft_link(&dir, &link)?;
+        rollback.push(&link);
+        drop(rollback);
+        assert!(!dir.exists(), "dir should be rolled back");
+        assert!(
+            link.symlink_metadata().is_err(),
+            "link should be rolled back"
+        );
+
+        let mut rollback = Rollback::new();
+        fs::create_dir(&dir)?;
+        rollback.push(&dir);
+        rollback.commit();
+        assert!(dir.exists(), "committed dir should be kept");
+        Ok(())
+    }
 }